@@ -0,0 +1,527 @@
+use std::{
+    fs::{File, Metadata},
+    io::{Read, Write, Cursor, Take, Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::Error,
+    io::{Input, Output},
+};
+
+
+/// Bytes every archive plaintext starts with
+pub const MAGIC: [u8; 6] = *b"CRYPAK";
+/// Current archive format version
+pub const VERSION: u8 = 1;
+/// Type id marking the end of archive instead of the next entry
+const END_TYPE_ID: u8 = 0;
+/// Size of entry header besides its path: type id, path size, mode, mtime seconds and nanoseconds, size
+const ENTRY_FIXED_SIZE: usize = 1 + 2 + 4 + 8 + 4 + 8;
+
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+impl EntryKind {
+    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
+        let res = match type_id {
+            1 => Self::File,
+            2 => Self::Dir,
+            _ => return Err(Error::make_spec("unable to define archive entry kind")),
+        };
+        Ok(res)
+    }
+
+    pub fn get_type_id(&self) -> u8 {
+        match self {
+            Self::File => 1,
+            Self::Dir => 2,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Dir => "dir",
+        }
+    }
+}
+
+
+/// Entry of archive, content of `size` bytes follows header of file entry
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Entry {
+    pub kind: EntryKind,
+    /// Relative path with `/` separators
+    pub path: String,
+    /// Unix permission bits
+    pub mode: u32,
+    pub mtime: SystemTime,
+    pub size: u64,
+}
+
+impl Entry {
+    /// Entry of file or directory on disk stored under name, returns None for anything else
+    pub fn from_metadata(name: String, meta: &Metadata) -> Option<Self> {
+        let kind = if meta.is_file() {
+            EntryKind::File
+        } else if meta.is_dir() {
+            EntryKind::Dir
+        } else {
+            return None;
+        };
+        let size = if kind == EntryKind::File { meta.len() } else { 0 };
+        let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
+        Some(Self { kind, path: name, mode: get_mode(meta), mtime, size })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut Output::new( Box::new(&mut bytes) ))
+            .expect("writing to vec should never fail");
+        bytes
+    }
+
+    fn write(&self, dst: &mut Output) -> Result<(), Error> {
+        let path_size = u16::try_from(self.path.len())
+            .map_err(|_| Error::make_arg(format!("path is too long to be archived: {}", self.path)))?;
+        let mtime = self.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        dst.write_u8(self.kind.get_type_id())?;
+        dst.write(&path_size.to_be_bytes())?;
+        dst.write(self.path.as_bytes())?;
+        dst.write_u32(self.mode)?;
+        dst.write(&mtime.as_secs().to_be_bytes())?;
+        dst.write_u32(mtime.subsec_nanos())?;
+        dst.write(&self.size.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn read(src: &mut Input) -> Result<Self, Error> {
+        let kind = EntryKind::from_type_id(src.read_u8()?)?;
+        let mut path_size = [0u8; 2];
+        src.read_exact(&mut path_size)?;
+        let mut path = vec![0u8; u16::from_be_bytes(path_size).into()];
+        src.read_exact(&mut path)?;
+        let path = String::from_utf8(path).map_err(|_| Error::make_spec("archive entry path should be utf-8"))?;
+        parse_rel_name(&path)?;
+        let mode = src.read_u32()?;
+        let (secs, nanos) = (read_u64(src)?, src.read_u32()?);
+        let mtime = Some(nanos).filter(|nanos| *nanos < 1_000_000_000)
+            .and_then(|nanos| UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+            .ok_or_else(|| Error::make_spec("archive entry modification time is out of range"))?;
+        let size = read_u64(src)?;
+        if kind == EntryKind::Dir && size != 0 {
+            return Err(Error::make_spec("archive directory entry should have no content"));
+        }
+        Ok(Self { kind, path, mode, mtime, size })
+    }
+}
+
+fn read_u64(src: &mut Input) -> Result<u64, Error> {
+    let mut arr = [0u8; 8];
+    src.read_exact(&mut arr)?;
+    Ok(u64::from_be_bytes(arr))
+}
+
+#[cfg(unix)]
+fn get_mode(meta: &Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn get_mode(meta: &Metadata) -> u32 {
+    let mode = if meta.is_dir() { 0o755 } else { 0o644 };
+    if meta.permissions().readonly() { mode & !0o222 } else { mode }
+}
+
+
+/// Name of relative path with `/` separators, e.g. to store it inside encrypted data
+pub fn make_rel_name(path: &Path) -> Result<String, Error> {
+    let parts = path.components()
+        .map(|part| match part {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|parts| !parts.is_empty())
+        .ok_or_else(|| Error::make_arg(format!("path should be relative utf-8 one: {}", path.display())))?;
+    Ok(parts.join("/"))
+}
+
+/// Parses name made by `make_rel_name`, only plain relative path is accepted,
+/// so path joined to a directory always stays inside it
+pub fn parse_rel_name(name: &str) -> Result<PathBuf, Error> {
+    let mut path = PathBuf::new();
+    for part in name.split('/') {
+        let mut components = Path::new(part).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            return Err(Error::make_spec(format!("name should be relative path: {}", name)));
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+
+/// Entry of archive with path of file or directory on disk it is made of
+pub struct Source {
+    pub path: PathBuf,
+    pub entry: Entry,
+}
+
+/// Makes sources of paths, directories are walked recursively and stored under their own names.
+/// Returns skipped paths as well: anything but files and directories, e.g. symbolic links
+pub fn collect_sources(paths: &[PathBuf]) -> Result<(Vec<Source>, Vec<PathBuf>), Error> {
+    let mut sources = Vec::new();
+    let mut skipped = Vec::new();
+    for path in paths {
+        let name = path.file_name().map(Path::new)
+            .ok_or_else(|| Error::make_arg(format!("path should name a file or directory: {}", path.display())))?;
+        let mut stack = vec![(path.clone(), make_rel_name(name)?)];
+        while let Some((path, name)) = stack.pop() {
+            let meta = path.symlink_metadata().map_err(Error::make_io)?;
+            let entry = match Entry::from_metadata(name, &meta) {
+                Some(entry) => entry,
+                None => {
+                    skipped.push(path);
+                    continue;
+                },
+            };
+            if entry.kind == EntryKind::Dir {
+                let mut children = std::fs::read_dir(&path).map_err(Error::make_io)?
+                    .map(|child| child.map(|child| child.file_name()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(Error::make_io)?;
+                // Stack pops the last child first, so children are pushed in reverse order
+                children.sort();
+                for child in children.iter().rev() {
+                    let child_name = make_rel_name(Path::new(child))?;
+                    stack.push((path.join(child), format!("{}/{}", entry.path, child_name)));
+                }
+            }
+            sources.push(Source { path, entry });
+        }
+    }
+    Ok((sources, skipped))
+}
+
+
+/// Reads archive made of sources, content of files is read one file at a time
+pub struct Packer {
+    sources: std::vec::IntoIter<Source>,
+    pending: Cursor<Vec<u8>>,
+    file: Option<(Take<File>, PathBuf)>,
+    is_done: bool,
+}
+
+impl Packer {
+    pub fn new(sources: Vec<Source>) -> Self {
+        let pending = Cursor::new([&MAGIC[..], &[VERSION]].concat());
+        Self { sources: sources.into_iter(), pending, file: None, is_done: false }
+    }
+
+    fn next_source(&mut self) -> IoResult<()> {
+        match self.sources.next() {
+            Some(Source { path, entry }) => {
+                self.pending = Cursor::new(entry.to_bytes());
+                if entry.kind == EntryKind::File {
+                    let file = File::open(&path)
+                        .map_err(|err| IoError::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+                    self.file = Some((file.take(entry.size), path));
+                }
+            },
+            None => {
+                self.pending = Cursor::new(vec![END_TYPE_ID]);
+                self.is_done = true;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Read for Packer {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        loop {
+            let size = self.pending.read(buf)?;
+            if size > 0 {
+                return Ok(size);
+            }
+            if let Some((file, path)) = &mut self.file {
+                let size = file.read(buf)?;
+                if size > 0 {
+                    return Ok(size);
+                }
+                // Size is already written into entry header
+                if file.limit() > 0 {
+                    return Err(IoError::new(IoErrorKind::UnexpectedEof, format!("{}: file shrank while archived", path.display())));
+                }
+                self.file = None;
+                continue;
+            }
+            if self.is_done {
+                return Ok(0);
+            }
+            self.next_source()?;
+        }
+    }
+}
+
+
+/// Receives entries of archive parsed by `Unpacker`
+pub trait Visitor {
+    /// Called with header of every entry
+    fn entry(&mut self, entry: &Entry) -> Result<(), Error>;
+    /// Called with parts of content of the last file entry
+    fn data(&mut self, data: &[u8]) -> Result<(), Error>;
+    /// Called after the whole content of entry is passed
+    fn entry_end(&mut self) -> Result<(), Error>;
+}
+
+
+/// Parses archive written into it and passes its entries to visitor, so archive is never kept in memory
+pub struct Unpacker<V: Visitor> {
+    visitor: V,
+    head: Vec<u8>,
+    left: Option<u64>,
+    is_started: bool,
+    is_done: bool,
+}
+
+impl <V: Visitor> Unpacker<V> {
+    pub fn new(visitor: V) -> Self {
+        Self { visitor, head: Vec::new(), left: None, is_started: false, is_done: false }
+    }
+
+    /// Checks archive is complete, returns visitor
+    pub fn finish(self) -> Result<V, Error> {
+        if !self.is_done {
+            return Err(Error::make_spec("archive ended before its end mark"));
+        }
+        Ok(self.visitor)
+    }
+
+    fn push(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        loop {
+            if self.is_done {
+                if !buf.is_empty() {
+                    return Err(Error::make_spec("archive has data after its end mark"));
+                }
+                return Ok(());
+            }
+            if let Some(left) = self.left {
+                if buf.is_empty() {
+                    return Ok(());
+                }
+                let size = left.min(buf.len() as u64) as usize;
+                self.visitor.data(&buf[..size])?;
+                buf = &buf[size..];
+                self.left = Some(left - size as u64).filter(|left| *left > 0);
+                if self.left.is_none() {
+                    self.visitor.entry_end()?;
+                }
+                continue;
+            }
+
+            // Header bytes are gathered until the whole header is here
+            let head_size = self.head_size();
+            if self.head.len() == head_size {
+                self.take_head()?;
+                continue;
+            }
+            if buf.is_empty() {
+                return Ok(());
+            }
+            let size = (head_size - self.head.len()).min(buf.len());
+            self.head.extend_from_slice(&buf[..size]);
+            buf = &buf[size..];
+        }
+    }
+
+    /// Size of header being gathered as far as its known part tells
+    fn head_size(&self) -> usize {
+        if !self.is_started {
+            return MAGIC.len() + 1;
+        }
+        match self.head.as_slice() {
+            [] | [END_TYPE_ID, ..] => 1,
+            [_, size_hi, size_lo, ..] => ENTRY_FIXED_SIZE + u16::from_be_bytes([*size_hi, *size_lo]) as usize,
+            _ => 3,
+        }
+    }
+
+    fn take_head(&mut self) -> Result<(), Error> {
+        let head = std::mem::take(&mut self.head);
+        if !self.is_started {
+            if head[..MAGIC.len()] != MAGIC {
+                return Err(Error::make_spec("data is not an archive"));
+            }
+            if head[MAGIC.len()] != VERSION {
+                return Err(Error::make_spec(format!("unsupported archive version: {}", head[MAGIC.len()])));
+            }
+            self.is_started = true;
+        } else if head == [END_TYPE_ID] {
+            self.is_done = true;
+        } else {
+            let entry = Entry::read(&mut Input::new( Box::new(&head[..]) ))?;
+            self.visitor.entry(&entry)?;
+            if entry.size > 0 {
+                self.left = Some(entry.size);
+            } else {
+                self.visitor.entry_end()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl <V: Visitor> Write for Unpacker<V> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.push(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        path::PathBuf,
+        time::{Duration, UNIX_EPOCH},
+    };
+    use rand::{RngCore, rngs::OsRng};
+    use crate::{error::Error, io::Input};
+    use super::{Entry, EntryKind, Source, Visitor, Packer, Unpacker, collect_sources};
+
+
+    #[test]
+    fn pack_unpack() {
+        let dir = make_dir();
+        std::fs::create_dir_all(dir.join("tree/sub")).unwrap();
+        std::fs::create_dir(dir.join("tree/empty")).unwrap();
+        let big = make_data(100_000);
+        std::fs::write(dir.join("tree/sub/big"), &big).unwrap();
+        std::fs::write(dir.join("tree/empty.txt"), b"").unwrap();
+        std::fs::write(dir.join("single"), b"single file").unwrap();
+
+        let (sources, skipped) = collect_sources(&[dir.join("tree"), dir.join("single")]).unwrap();
+        assert!(skipped.is_empty());
+        let names = sources.iter().map(|source| source.entry.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["tree", "tree/empty", "tree/empty.txt", "tree/sub", "tree/sub/big", "single"], names);
+        let entries = sources.iter().map(|source| source.entry.clone()).collect::<Vec<_>>();
+
+        let mut archive = Vec::new();
+        Packer::new(sources).read_to_end(&mut archive).unwrap();
+
+        // Byte by byte writes to cross every header border
+        let mut unpacker = Unpacker::new(Recorder::default());
+        for byte in &archive {
+            unpacker.write_all(&[*byte]).unwrap();
+        }
+        let recorder = unpacker.finish().unwrap();
+        assert_eq!(entries, recorder.entries.iter().map(|(entry, _)| entry.clone()).collect::<Vec<_>>());
+        assert_eq!(big, recorder.entries[4].1);
+        assert_eq!(b"single file", &recorder.entries[5].1[..]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn unpack_broken() {
+        let entry = Entry { kind: EntryKind::File, path: "file".to_owned(), mode: 0o644, mtime: UNIX_EPOCH + Duration::new(7, 9), size: 3 };
+        let archive = [&super::MAGIC[..], &[super::VERSION], &entry.to_bytes(), b"abc", &[0]].concat();
+
+        for size in 0..archive.len() {
+            let mut unpacker = Unpacker::new(Recorder::default());
+            unpacker.write_all(&archive[..size]).unwrap();
+            assert!(matches!(unpacker.finish(), Err(Error::Spec(_))), "size: {}", size);
+        }
+        let mut unpacker = Unpacker::new(Recorder::default());
+        assert!(unpacker.write_all(&[&archive[..], &[0]].concat()).is_err());
+
+        let entry = Entry { path: "../file".to_owned(), ..entry };
+        let archive = [&super::MAGIC[..], &[super::VERSION], &entry.to_bytes()].concat();
+        assert!(Unpacker::new(Recorder::default()).write_all(&archive).is_err());
+    }
+
+
+    #[test]
+    fn entry_mtime_out_of_range() {
+        let entry = Entry { kind: EntryKind::File, path: "file".to_owned(), mode: 0o644, mtime: UNIX_EPOCH + Duration::new(7, 9), size: 3 };
+        let bytes = entry.to_bytes();
+        assert_eq!(entry, Entry::read(&mut Input::new( Box::new(&bytes[..]) )).unwrap());
+
+        // Seconds and nanoseconds follow kind, path size, path and mode
+        let offset = 1 + 2 + entry.path.len() + 4;
+        for (secs, nanos) in [(7, 1_000_000_000), (u64::MAX, 999_999_999), (u64::MAX, u32::MAX)] {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 8].copy_from_slice(&u64::to_be_bytes(secs));
+            bytes[offset + 8..offset + 12].copy_from_slice(&nanos.to_be_bytes());
+            let res = Entry::read(&mut Input::new( Box::new(&bytes[..]) ));
+            assert!(matches!(res, Err(Error::Spec(_))), "secs: {}, nanos: {}", secs, nanos);
+        }
+    }
+
+
+    #[test]
+    fn pack_shrunk_file() {
+        let dir = make_dir();
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        let (mut sources, _) = collect_sources(&[dir.join("file")]).unwrap();
+        sources[0].entry.size += 1;
+        let res = Packer::new(sources).read_to_end(&mut Vec::new());
+        assert!(res.is_err());
+
+        let entry = Entry { kind: EntryKind::File, path: "missing".to_owned(), mode: 0o644, mtime: UNIX_EPOCH, size: 1 };
+        let missing = Source { path: dir.join("missing"), entry };
+        assert!(Packer::new(vec![missing]).read_to_end(&mut Vec::new()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[derive(Default)]
+    struct Recorder {
+        entries: Vec<(Entry, Vec<u8>)>,
+    }
+
+    impl Visitor for Recorder {
+        fn entry(&mut self, entry: &Entry) -> Result<(), Error> {
+            self.entries.push((entry.clone(), Vec::new()));
+            Ok(())
+        }
+
+        fn data(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.entries.last_mut().unwrap().1.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn entry_end(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+
+    fn make_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crydec-test-{:016x}", OsRng.next_u64()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+
+    fn make_data(size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+}
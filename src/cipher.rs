@@ -1,161 +1,277 @@
-use std::ops::Sub;
+use std::{
+    collections::BTreeMap,
+    ops::Sub,
+    sync::{Mutex, mpsc},
+};
 
 use aead::{
-    KeyInit, AeadInPlace, AeadCore,
-    stream::{EncryptorBE32, DecryptorBE32}, 
+    KeyInit, AeadInPlace, AeadCore, Payload,
+    stream::{NewStream, StreamBE32, StreamPrimitive},
     generic_array::{typenum::U5, ArrayLength},
 };
 use aes_gcm::{Aes256Gcm, Aes128Gcm};
 use chacha20poly1305::{XChaCha20Poly1305, XChaCha12Poly1305, XChaCha8Poly1305, ChaCha20Poly1305, ChaCha12Poly1305, ChaCha8Poly1305};
+use clap::ValueEnum;
 
-pub use crate::{
-    cli::Cipher as CipherType,
-    error::Error,
+use crate::{
+    error::{Error, ChunkPos},
     io::{Input, Output},
 };
 
 
-pub const BUFFER_LEN_ENC: usize = 500;
-pub const BUFFER_LEN_DEC: usize =  BUFFER_LEN_ENC + 16;
+/// Size of authentication tag appended to every encrypted chunk
+pub const TAG_SIZE: usize = 16;
+/// Default size of plaintext chunk
+pub const CHUNK_SIZE_DEFAULT: u32 = 64 * 1024;
+pub const CHUNK_SIZE_MIN: u32 = 1024;
+pub const CHUNK_SIZE_MAX: u32 = 16 * 1024 * 1024;
+
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+#[cfg_attr(test, derive(strum::EnumIter))]
+pub enum Cipher {
+    XChacha20Poly1305,
+    XChacha12Poly1305,
+    XChacha8Poly1305,
+    Chacha20Poly1305,
+    Chacha12Poly1305,
+    Chacha8Poly1305,
+    Aes256Gcm,
+    Aes128Gcm,
+}
 
+impl Cipher {
+    /// Returns cipher type by type_id
+    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
+        let res = match type_id {
+            0 => Self::XChacha20Poly1305,
+            1 => Self::XChacha12Poly1305,
+            2 => Self::XChacha8Poly1305,
+            3 => Self::Chacha20Poly1305,
+            4 => Self::Chacha12Poly1305,
+            5 => Self::Chacha8Poly1305,
+            6 => Self::Aes256Gcm,
+            7 => Self::Aes128Gcm,
+            _ => return Err(Error::make_spec("unable to define cipher type")),
+        };
+        Ok(res)
+    }
 
-pub fn encrypt(cipher: CipherType, key: &[u8], nonce: &[u8], src: &mut Input, dst: &mut Output, buf: [u8; BUFFER_LEN_ENC], nread: usize) -> Result<(), Error> {
-    // TODO: catch panic!
-    match cipher {
-        CipherType::XChacha20Poly1305 => {
-            let aead = XChaCha20Poly1305::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::XChacha12Poly1305 => {
-            let aead = XChaCha12Poly1305::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::XChacha8Poly1305 => {
-            let aead = XChaCha8Poly1305::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::Chacha20Poly1305 => {
-            let aead = ChaCha20Poly1305::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::Chacha12Poly1305 => {
-            let aead = ChaCha12Poly1305::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::Chacha8Poly1305 => {
-            let aead = ChaCha8Poly1305::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::Aes256Gcm => {
-            let aead = Aes256Gcm::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
-        CipherType::Aes128Gcm => {
-            let aead = Aes128Gcm::new(key.into());
-            let encryptor = EncryptorBE32::from_aead(aead, nonce.into());
-            transfer_encryption(encryptor, src, dst, buf, nread)?;
-        },
+    /// Returns (type_id, key_size, nonce_size) 
+    pub fn get_spec(&self) -> (u8, u32, usize) {
+        match self {
+            Self::XChacha20Poly1305 => (0, 32, 24),
+            Self::XChacha12Poly1305 => (1, 32, 24),
+            Self::XChacha8Poly1305 => (2, 32, 24),
+            Self::Chacha20Poly1305 => (3, 32, 12),
+            Self::Chacha12Poly1305 => (4, 32, 12),
+            Self::Chacha8Poly1305 => (5, 32, 12),
+            Self::Aes256Gcm => (6, 32, 12),
+            Self::Aes128Gcm => (7, 16, 12),
+        }
     }
-    Ok(())
 }
 
-pub fn decrypt(cipher: CipherType, key: &[u8], nonce: &[u8], src: &mut Input, dst: &mut Output, buf: [u8; BUFFER_LEN_DEC], nread: usize) -> Result<(), Error> {
+
+/// STREAM construction with erased AEAD algorithm. Nonce of every chunk depends 
+/// only on its position and last flag, so chunks could be processed independently.
+pub trait ChunkCipher: Send + Sync {
+    fn encrypt_chunk(&self, position: u32, last: bool, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decrypt_chunk(&self, position: u32, last: bool, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+impl <T> ChunkCipher for StreamBE32<T>
+    where
+        T: AeadInPlace + Send + Sync,
+        T::NonceSize: Sub<U5>,
+        <<T as AeadCore>::NonceSize as Sub<U5>>::Output: ArrayLength<u8>
+{
+    fn encrypt_chunk(&self, position: u32, last: bool, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        // Counter of not last chunk must not overflow, the same as EncryptorBE32 checks
+        if !last && position == Self::COUNTER_MAX {
+            return Err(Error::make_aead(aead::Error));
+        }
+        StreamPrimitive::encrypt(self, position, last, Payload { msg, aad }).map_err(Error::make_aead)
+    }
+
+    fn decrypt_chunk(&self, position: u32, last: bool, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if !last && position == Self::COUNTER_MAX {
+            return Err(Error::make_aead(aead::Error));
+        }
+        StreamPrimitive::decrypt(self, position, last, Payload { msg, aad }).map_err(Error::make_aead)
+    }
+}
+
+
+pub fn make_stream(cipher: Cipher, key: &[u8], nonce: &[u8]) -> Box<dyn ChunkCipher> {
     // TODO: catch panic!
     match cipher {
-        CipherType::XChacha20Poly1305 => {
+        Cipher::XChacha20Poly1305 => {
             let aead = XChaCha20Poly1305::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::XChacha12Poly1305 => {
+        Cipher::XChacha12Poly1305 => {
             let aead = XChaCha12Poly1305::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::XChacha8Poly1305 => {
+        Cipher::XChacha8Poly1305 => {
             let aead = XChaCha8Poly1305::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::Chacha20Poly1305 => {
+        Cipher::Chacha20Poly1305 => {
             let aead = ChaCha20Poly1305::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::Chacha12Poly1305 => {
+        Cipher::Chacha12Poly1305 => {
             let aead = ChaCha12Poly1305::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::Chacha8Poly1305 => {
+        Cipher::Chacha8Poly1305 => {
             let aead = ChaCha8Poly1305::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::Aes256Gcm => {
+        Cipher::Aes256Gcm => {
             let aead = Aes256Gcm::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
-        CipherType::Aes128Gcm => {
+        Cipher::Aes128Gcm => {
             let aead = Aes128Gcm::new(key.into());
-            let encryptor = DecryptorBE32::from_aead(aead, nonce.into());
-            transfer_decryption(encryptor, src, dst, buf, nread)?;
+            Box::new( StreamBE32::from_aead(aead, nonce.into()) )
         },
     }
+}
+
+
+/// Encrypts src by chunks of chunk_size plaintext bytes, threads chunks are encrypted at once
+pub fn encrypt(stream: &dyn ChunkCipher, aad: &[u8], chunk_size: usize, threads: usize, src: &mut Input, dst: &mut Output) -> Result<(), Error> {
+    let mut index = 0u64;
+    let mut is_last = false;
+    let next = || {
+        if is_last {
+            return Ok(None);
+        }
+        // Short read means the end of input, so it is the last chunk
+        let mut data = vec![0u8; chunk_size];
+        let read_count = src.read(&mut data)?;
+        data.truncate(read_count);
+        is_last = read_count < chunk_size;
+        index += 1;
+        Ok(Some(Chunk { index: index - 1, last: is_last, data }))
+    };
+    process_chunks(threads, next, |chunk| {
+        stream.encrypt_chunk(chunk_position(chunk.index)?, chunk.last, &chunk.data, aad)
+    }, |ciphertext| dst.write(&ciphertext))
+}
+
+/// Decrypts src by chunks of chunk_size plaintext bytes plus tag, threads chunks are decrypted at once
+pub fn decrypt(stream: &dyn ChunkCipher, aad: &[u8], chunk_size: usize, threads: usize, src: &mut Input, dst: &mut Output) -> Result<(), Error> {
+    let chunk_len = chunk_size + TAG_SIZE;
+    let mut index = 0u64;
+    let mut is_last = false;
+    let next = || {
+        if is_last {
+            return Ok(None);
+        }
+        // Short read means the end of input, so it is the last chunk
+        let mut data = vec![0u8; chunk_len];
+        let read_count = src.read(&mut data)?;
+        if read_count == 0 {
+            return Ok(None);
+        }
+        data.truncate(read_count);
+        is_last = read_count < chunk_len;
+        index += 1;
+        Ok(Some(Chunk { index: index - 1, last: is_last, data }))
+    };
+    process_chunks(threads, next, |chunk| {
+        stream.decrypt_chunk(chunk_position(chunk.index)?, chunk.last, &chunk.data, aad)
+            .map_err(|_| Error::make_chunk_failure(ChunkPos::new(chunk.index, chunk_len, chunk.last)))
+    }, |plaintext| dst.write(&plaintext))?;
+
+    // Input ended before the last chunk, so the rest of stream is cut off
+    if !is_last {
+        return Err(Error::make_truncated(ChunkPos::new(index, chunk_len, false)));
+    }
     Ok(())
 }
 
 
-fn transfer_encryption<T>(mut encryptor: EncryptorBE32<T>, src: &mut Input, dst: &mut Output, mut buf: [u8; BUFFER_LEN_ENC], nread: usize) -> Result<(), Error> 
+fn chunk_position(index: u64) -> Result<u32, Error> {
+    u32::try_from(index).map_err(|_| Error::make_aead(aead::Error))
+}
+
+/// Chunk of stream, chunks are numbered from 0 in order of stream
+struct Chunk {
+    index: u64,
+    last: bool,
+    data: Vec<u8>,
+}
+
+/// Applies func to every chunk returned by next, results are passed to write in order of chunks.
+/// Chunks are processed by threads workers started once, up to twice as many chunks are in flight
+fn process_chunks<N, F, W>(threads: usize, mut next: N, func: F, mut write: W) -> Result<(), Error>
     where
-        T: AeadInPlace,
-        T::NonceSize: Sub<U5>,
-        <<T as AeadCore>::NonceSize as Sub<U5>>::Output: ArrayLength<u8>
+        N: FnMut() -> Result<Option<Chunk>, Error>,
+        F: Fn(&Chunk) -> Result<Vec<u8>, Error> + Sync,
+        W: FnMut(Vec<u8>) -> Result<(), Error>,
 {
-    let mut read_count = nread;
-    loop {
-        if read_count == BUFFER_LEN_ENC {
-            let ciphertext= encryptor.encrypt_next(buf.as_slice()).map_err(Error::make_aead)?;
-            dst.write(&ciphertext)?;
-        } else {
-            let ciphertext = encryptor.encrypt_last(&buf[..read_count]).map_err(Error::make_aead)?;
-            dst.write(&ciphertext)?;
-            break;
+    if threads < 2 {
+        while let Some(chunk) = next()? {
+            write(func(&chunk)?)?;
         }
-        read_count = src.read(&mut buf)?;
+        return Ok(());
     }
-    Ok(())
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<Chunk>(threads);
+    let chunk_rx = Mutex::new(chunk_rx);
+    let (res_tx, res_rx) = mpsc::channel();
+    let func = &func;
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let (chunk_rx, res_tx) = (&chunk_rx, res_tx.clone());
+            scope.spawn(move || loop {
+                // Queue is locked only to take a chunk, so chunks are processed in parallel
+                let chunk = chunk_rx.lock().expect("chunk queue should not be poisoned").recv();
+                let Ok(chunk) = chunk else { break };
+                if res_tx.send((chunk.index, func(&chunk))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(res_tx);
+        // Workers stop as soon as sender of chunks is dropped, either on the end or on error
+        feed_chunks(threads * 2, chunk_tx, &res_rx, &mut next, &mut write)
+    })
 }
 
-fn transfer_decryption<T>(mut decryptor: DecryptorBE32<T>, src: &mut Input, dst: &mut Output, mut buf: [u8; BUFFER_LEN_DEC], nread: usize) -> Result<(), Error>
+/// Sends chunks to workers keeping up to limit of them in flight, writes results in order of chunks
+fn feed_chunks<N, W>(limit: usize, chunk_tx: mpsc::SyncSender<Chunk>, res_rx: &mpsc::Receiver<(u64, Result<Vec<u8>, Error>)>, next: &mut N, write: &mut W) -> Result<(), Error>
     where
-        T: aead::AeadInPlace,
-        T::NonceSize: std::ops::Sub<U5>,
-        <<T as aead::AeadCore>::NonceSize as std::ops::Sub<U5>>::Output: ArrayLength<u8>
+        N: FnMut() -> Result<Option<Chunk>, Error>,
+        W: FnMut(Vec<u8>) -> Result<(), Error>,
 {
-    let mut read_count = nread;
+    let mut pending = BTreeMap::new();
+    let (mut sent, mut written) = (0u64, 0u64);
+    let mut is_end = false;
     loop {
-        if read_count == BUFFER_LEN_DEC {
-            let plaintext = decryptor.decrypt_next(buf.as_slice()).map_err(Error::make_aead)?;
-                dst.write(&plaintext)?;
-        } else if read_count == 0 {
-            break;
-        } else {
-            let plaintext = decryptor.decrypt_last(&buf[..read_count]).map_err(Error::make_aead)?;
-            dst.write(&plaintext)?;
-            break;
+        while !is_end && sent - written < limit as u64 {
+            match next()? {
+                Some(chunk) => {
+                    chunk_tx.send(chunk).expect("workers should wait for chunks");
+                    sent += 1;
+                },
+                None => is_end = true,
+            }
+        }
+        if written == sent {
+            return Ok(());
+        }
+        let (index, res) = res_rx.recv().expect("workers should send result of every chunk");
+        pending.insert(index, res);
+        while let Some(res) = pending.remove(&written) {
+            write(res?)?;
+            written += 1;
         }
-        read_count = src.read(&mut buf)?;
     }
-    Ok(())
 }
 
 
@@ -171,15 +287,19 @@ mod tests {
     use rand::{RngCore, rngs::OsRng};
     use strum::IntoEnumIterator;
     use crate::{
-        cli::Cipher,
+        error::{Error, ChunkPos},
         io::{Input, Output},
     };
-    use super::{BUFFER_LEN_ENC, BUFFER_LEN_DEC, encrypt, decrypt};
+    use super::{Cipher, make_stream, encrypt, decrypt, TAG_SIZE};
+
+
+    const AAD: &[u8] = b"serialized header";
+    const CHUNK_SIZE: usize = 500;
 
 
     #[test]
     fn encrypt_decrypt() {
-        let size_data = BUFFER_LEN_ENC * 3 + 1;
+        let size_data = CHUNK_SIZE * 3 + 1;
         for cipher in Cipher::iter() {
             let (_, key_size, mut nonce_size) = cipher.get_spec();
             nonce_size -= 5;
@@ -209,9 +329,7 @@ mod tests {
             {
                 let mut input = Input::new(Box::new( input_reader ));
                 let mut output = Output::new(Box::new( enc_writer ));
-                let mut buf = [0u8; BUFFER_LEN_ENC];
-                let nread = input.read(&mut buf).unwrap();
-                encrypt(cipher, &key[..], nonce, &mut input, &mut output, buf, nread).unwrap();
+                encrypt(&*make_stream(cipher, &key[..], nonce), AAD, CHUNK_SIZE, 1, &mut input, &mut output).unwrap();
             }
             
             // Check encrypted
@@ -226,9 +344,7 @@ mod tests {
             {
                 let mut input = Input::new(Box::new( input_reader ));
                 let mut output = Output::new(Box::new( dec_writer ));
-                let mut buf = [0u8; BUFFER_LEN_DEC];
-                let nread = input.read(&mut buf).unwrap();
-                decrypt(cipher, &key[..], nonce, &mut input, &mut output, buf, nread).unwrap();
+                decrypt(&*make_stream(cipher, &key[..], nonce), AAD, CHUNK_SIZE, 1, &mut input, &mut output).unwrap();
             }
 
             // Check decrypted
@@ -238,6 +354,139 @@ mod tests {
     }
 
 
+    #[test]
+    fn decrypt_wrong_aad() {
+        for cipher in Cipher::iter() {
+            let (_, key_size, mut nonce_size) = cipher.get_spec();
+            nonce_size -= 5;
+
+            let mut key: Vec<u8> = vec![0u8; key_size.try_into().unwrap()];
+            OsRng.fill_bytes(&mut key);
+            let mut nonce_arr = [0u8; 19];
+            let nonce = &mut nonce_arr[..nonce_size];
+            OsRng.fill_bytes(nonce);
+
+            let mut data = vec![0u8; CHUNK_SIZE + 1];
+            OsRng.fill_bytes(&mut data);
+            let data_enc = Rc::new(RefCell::new( Vec::new() ));
+            {
+                let mut input = Input::new(Box::new( MockRead::new(data) ));
+                let mut output = Output::new(Box::new( MockWrite::new(data_enc.clone()) ));
+                encrypt(&*make_stream(cipher, &key[..], nonce), AAD, CHUNK_SIZE, 1, &mut input, &mut output).unwrap();
+            }
+
+            let data_enc: Vec<u8> = Rc::try_unwrap(data_enc).unwrap().into_inner();
+            let mut input = Input::new(Box::new( MockRead::new(data_enc) ));
+            let mut output = Output::new(Box::new( MockWrite::new(Rc::new(RefCell::new( Vec::new() ))) ));
+            let res = decrypt(&*make_stream(cipher, &key[..], nonce), b"tampered header", CHUNK_SIZE, 1, &mut input, &mut output);
+            assert!(matches!(res, Err(Error::Corrupted(ChunkPos { index: 0, offset: 0, .. }))), "tampered aad accepted, cipher: {:?}", cipher);
+        }
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_threads() {
+        for size_data in [0, CHUNK_SIZE, CHUNK_SIZE * 7 + 3, CHUNK_SIZE * 8] {
+            for cipher in Cipher::iter() {
+                let (_, key_size, _) = cipher.get_spec();
+                let mut key = vec![0u8; key_size.try_into().unwrap()];
+                OsRng.fill_bytes(&mut key);
+                let mut nonce = vec![0u8; cipher.get_spec().2 - 5];
+                OsRng.fill_bytes(&mut nonce);
+                let stream = make_stream(cipher, &key, &nonce);
+
+                let mut data = vec![0u8; size_data];
+                OsRng.fill_bytes(&mut data);
+
+                let mut enc_expected = Vec::new();
+                encrypt(&*stream, AAD, CHUNK_SIZE, 1, &mut Input::new(Box::new( &data[..] )), &mut Output::new(Box::new( &mut enc_expected ))).unwrap();
+                for threads in [2, 3, 8] {
+                    // Output has to be byte-identical to the single-threaded one
+                    let mut enc_fact = Vec::new();
+                    encrypt(&*stream, AAD, CHUNK_SIZE, threads, &mut Input::new(Box::new( &data[..] )), &mut Output::new(Box::new( &mut enc_fact ))).unwrap();
+                    assert_eq!(enc_expected, enc_fact, "size: {}, threads: {}, cipher: {:?}", size_data, threads, cipher);
+
+                    let mut dec_fact = Vec::new();
+                    decrypt(&*stream, AAD, CHUNK_SIZE, threads, &mut Input::new(Box::new( &enc_fact[..] )), &mut Output::new(Box::new( &mut dec_fact ))).unwrap();
+                    assert_eq!(data, dec_fact, "size: {}, threads: {}, cipher: {:?}", size_data, threads, cipher);
+                }
+            }
+        }
+    }
+
+
+    #[test]
+    fn decrypt_threads_tampered() {
+        let cipher = Cipher::XChacha20Poly1305;
+        let stream = make_stream(cipher, &[7u8; 32], &[9u8; 19]);
+        let data = vec![1u8; CHUNK_SIZE * 5];
+        let mut enc = Vec::new();
+        encrypt(&*stream, AAD, CHUNK_SIZE, 4, &mut Input::new(Box::new( &data[..] )), &mut Output::new(Box::new( &mut enc ))).unwrap();
+        enc[CHUNK_SIZE * 3] ^= 1;
+
+        let mut dec = Vec::new();
+        let res = decrypt(&*stream, AAD, CHUNK_SIZE, 4, &mut Input::new(Box::new( &enc[..] )), &mut Output::new(Box::new( &mut dec )));
+        let offset = (CHUNK_SIZE + TAG_SIZE) as u64 * 2;
+        assert!(matches!(res, Err(Error::Corrupted(ChunkPos { index: 2, last: false, offset: o })) if o == offset));
+        // Chunks before the tampered one are still written in order
+        assert_eq!(&data[..CHUNK_SIZE * 2], &dec[..]);
+    }
+
+
+    #[test]
+    fn decrypt_truncated() {
+        let stream = make_stream(Cipher::XChacha20Poly1305, &[7u8; 32], &[9u8; 19]);
+        let data = vec![1u8; CHUNK_SIZE * 3 + 1];
+        let mut enc = Vec::new();
+        encrypt(&*stream, AAD, CHUNK_SIZE, 1, &mut Input::new(Box::new( &data[..] )), &mut Output::new(Box::new( &mut enc ))).unwrap();
+        enc.truncate(enc.len() - 1 - TAG_SIZE);
+
+        let res = decrypt(&*stream, AAD, CHUNK_SIZE, 1, &mut Input::new(Box::new( &enc[..] )), &mut Output::new(Box::new( std::io::sink() )));
+        let offset = (CHUNK_SIZE + TAG_SIZE) as u64 * 3;
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 3, last: false, offset: o })) if o == offset));
+    }
+
+
+    #[test]
+    fn decrypt_truncated_inside_chunk() {
+        let stream = make_stream(Cipher::XChacha20Poly1305, &[7u8; 32], &[9u8; 19]);
+        let data = vec![1u8; CHUNK_SIZE * 3 + 1];
+        let mut enc = Vec::new();
+        encrypt(&*stream, AAD, CHUNK_SIZE, 1, &mut Input::new(Box::new( &data[..] )), &mut Output::new(Box::new( &mut enc ))).unwrap();
+
+        // Cut chunk is read as the last one, so it is reported as truncated rather than corrupted
+        let chunk_len = CHUNK_SIZE + TAG_SIZE;
+        for cut in [chunk_len / 2, chunk_len * 2 + 1, enc.len() - 1] {
+            for threads in [1, 2] {
+                let res = decrypt(&*stream, AAD, CHUNK_SIZE, threads, &mut Input::new(Box::new( &enc[..cut] )), &mut Output::new(Box::new( std::io::sink() )));
+                let index = (cut / chunk_len) as u64;
+                assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: i, last: true, .. })) if i == index), "cut: {}, res: {:?}", cut, res);
+            }
+        }
+    }
+
+
+    #[test]
+    fn decrypt_truncated_boundaries() {
+        let stream = make_stream(Cipher::XChacha20Poly1305, &[7u8; 32], &[9u8; 19]);
+        for size in [0, CHUNK_SIZE * 3, CHUNK_SIZE * 3 + 1] {
+            let data = vec![1u8; size];
+            let mut enc = Vec::new();
+            encrypt(&*stream, AAD, CHUNK_SIZE, 1, &mut Input::new(Box::new( &data[..] )), &mut Output::new(Box::new( &mut enc ))).unwrap();
+
+            // Every cut on chunk boundary before the end drops the last chunk
+            let chunk_len = CHUNK_SIZE + TAG_SIZE;
+            for idx in 0..=(enc.len() - 1) / chunk_len {
+                for threads in [1, 2] {
+                    let res = decrypt(&*stream, AAD, CHUNK_SIZE, threads, &mut Input::new(Box::new( &enc[..idx * chunk_len] )), &mut Output::new(Box::new( std::io::sink() )));
+                    assert!(matches!(res, Err(Error::Truncated(ChunkPos { index, .. })) if index == idx as u64),
+                        "size: {}, cut: {}, threads: {}, res: {:?}", size, idx, threads, res);
+                }
+            }
+        }
+    }
+
+
     struct MockRead<T: AsRef<[u8]>> {
         inner: T,
         idx_start: usize,
@@ -1,14 +1,29 @@
 use std::path::PathBuf;
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap::{Args, ArgGroup, Parser, Subcommand, ValueEnum};
 
-use crate::error::Error;
+use crydec::{
+    cipher::{Cipher, CHUNK_SIZE_MIN, CHUNK_SIZE_MAX},
+    hash::{HashParams, ArgonVariant, ArgonVersion},
+    recipient::Recipient,
+};
+
+use crate::control::PwdSource;
 
 #[derive(Parser, Debug)]
 #[command(about, version, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    /// format of error printed to stderr
+    #[arg(long, global=true, value_enum, default_value_t=ErrorFormat::Text)]
+    pub error_format: ErrorFormat,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ErrorFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand, Debug)]
@@ -17,6 +32,22 @@ pub enum Commands {
     Encrypt(Encrypt),
     /// Decrypt data from stdin or file (--fin) to stdout or file (--fout)
     Decrypt(Decrypt),
+    /// Decrypt data from stdin or file (--fin) discarding plaintext to check its integrity
+    Verify(Verify),
+    /// Decrypt only plaintext range of file (--fin) to stdout, chunks out of range are not touched
+    Cat(Cat),
+    /// Replace key slot of encrypted file (--fin) or spec file (--fspec), data is not re-encrypted
+    Rekey(Rekey),
+    /// Print spec of encrypted file (--fin) or spec file (--fspec) without asking for any secret
+    Inspect(Inspect),
+    /// Generate X25519 identity file and its public key to encrypt for
+    Keygen(Keygen),
+    /// Bundle files and directories into one encrypted archive written to stdout or file (--fout)
+    Pack(Pack),
+    /// Extract encrypted archive from stdin or file (--fin) into directory (--out-dir)
+    Unpack(Unpack),
+    /// Print entries of encrypted archive from stdin or file (--fin)
+    List(List),
 }
 
 
@@ -24,15 +55,13 @@ pub enum Commands {
 pub struct Encrypt {
     #[clap(flatten)]
     pub io: CfgIo,
-    #[arg(long, value_enum, default_value_t=Cipher::XChacha20Poly1305)]
-    pub cipher: Cipher,
     #[clap(flatten)]
-    pub hash: CfgHash,
+    pub tree: CfgTree,
+    /// add data as a new segment of --fout file keeping its spec, the file is created if missing
+    #[arg(long, requires="fout", conflicts_with_all=["fspec", "in_place", "recursive", "force"])]
+    pub append: bool,
     #[clap(flatten)]
-    pub rand: CfgRand,
-    /// (insecure) password as cli argument
-    #[arg(long)]
-    pub pwd_cli: Option<String>,
+    pub enc: CfgEncrypt,
 }
 
 
@@ -40,9 +69,167 @@ pub struct Encrypt {
 pub struct Decrypt {
     #[clap(flatten)]
     pub io: CfgIo,
-    /// (insecure) password as cli argument
+    #[clap(flatten)]
+    pub tree: CfgTree,
+    /// number of threads to decrypt chunks in parallel
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u16).range(1..))]
+    pub threads: u16,
+    #[clap(flatten)]
+    pub secret: CfgSecret,
+}
+
+
+#[derive(Args, Debug)]
+pub struct Verify {
+    /// use file as input instead of stdin
     #[arg(long)]
-    pub pwd_cli: Option<String>,
+    pub fin: Option<PathBuf>,
+    /// read spec from file instead of input
+    #[arg(long)]
+    pub fspec: Option<PathBuf>,
+    /// number of threads to decrypt chunks in parallel
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u16).range(1..))]
+    pub threads: u16,
+    #[clap(flatten)]
+    pub secret: CfgSecret,
+}
+
+
+#[derive(Args, Debug)]
+pub struct Cat {
+    /// encrypted file, it has to be seekable
+    #[arg(long)]
+    pub fin: PathBuf,
+    /// read spec from file instead of input
+    #[arg(long)]
+    pub fspec: Option<PathBuf>,
+    /// plaintext offset to start from, negative one counts from the end
+    #[arg(long, default_value_t=0, allow_negative_numbers=true)]
+    pub offset: i64,
+    /// number of plaintext bytes to write, up to the end if not set
+    #[arg(long)]
+    pub length: Option<u64>,
+    #[clap(flatten)]
+    pub secret: CfgSecret,
+}
+
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("target").required(true).args(["fin", "fspec"])))]
+pub struct Rekey {
+    /// encrypted file with spec in header
+    #[arg(long)]
+    pub fin: Option<PathBuf>,
+    /// spec file of encrypted data
+    #[arg(long, conflicts_with="fin")]
+    pub fspec: Option<PathBuf>,
+    #[clap(flatten)]
+    pub secret: CfgSecret,
+    /// key file replacing the old one, the old one is kept if not set
+    #[arg(long)]
+    pub new_key_file: Option<PathBuf>,
+    /// public key replacing identity of the old key slot
+    #[arg(long)]
+    pub new_recipient: Option<Recipient>,
+    /// (insecure) new password as cli argument
+    #[arg(long)]
+    pub new_pwd_cli: Option<String>,
+    /// new password from environment variable
+    #[arg(long, conflicts_with="new_pwd_cli")]
+    pub new_pwd_env: Option<String>,
+    /// new password read up to the end of open file descriptor
+    #[arg(long, conflicts_with_all=["new_pwd_cli", "new_pwd_env"])]
+    pub new_pwd_fd: Option<i32>,
+    /// new password read from file
+    #[arg(long, conflicts_with_all=["new_pwd_cli", "new_pwd_env", "new_pwd_fd"])]
+    pub new_pwd_file: Option<PathBuf>,
+    /// new password printed to stdout by shell command
+    #[arg(long, conflicts_with_all=["new_pwd_cli", "new_pwd_env", "new_pwd_fd", "new_pwd_file"])]
+    pub new_pwd_cmd: Option<String>,
+    #[clap(flatten)]
+    pub hash: CfgRehash,
+}
+
+impl Rekey {
+    pub fn new_pwd_source(&self) -> Option<PwdSource> {
+        make_pwd_source(&self.new_pwd_cli, &self.new_pwd_env, self.new_pwd_fd, &self.new_pwd_file, &self.new_pwd_cmd)
+    }
+}
+
+
+#[derive(Args, Debug)]
+pub struct Inspect {
+    /// use file as input instead of stdin
+    #[arg(long)]
+    pub fin: Option<PathBuf>,
+    /// read spec from file instead of input
+    #[arg(long)]
+    pub fspec: Option<PathBuf>,
+    /// print spec as json
+    #[arg(long)]
+    pub json: bool,
+}
+
+
+#[derive(Args, Debug)]
+pub struct Keygen {
+    /// file to write identity to, existing file is never overwritten
+    #[arg(long)]
+    pub identity: PathBuf,
+    /// file to write public key to instead of stdout
+    #[arg(long)]
+    pub public: Option<PathBuf>,
+    /// overwrite existing public key file, identity file is never overwritten
+    #[arg(long, requires="public")]
+    pub force: bool,
+}
+
+
+#[derive(Args, Debug)]
+pub struct Pack {
+    /// files and directories to bundle, directories are added recursively
+    #[arg(required=true)]
+    pub paths: Vec<PathBuf>,
+    /// use file as output instead of stdout
+    #[arg(long)]
+    pub fout: Option<PathBuf>,
+    /// overwrite existing output file
+    #[arg(long)]
+    pub force: bool,
+    #[clap(flatten)]
+    pub enc: CfgEncrypt,
+}
+
+
+#[derive(Args, Debug)]
+pub struct Unpack {
+    /// use file as input instead of stdin
+    #[arg(long)]
+    pub fin: Option<PathBuf>,
+    /// directory to extract archive to
+    #[arg(long, default_value=".")]
+    pub out_dir: PathBuf,
+    /// overwrite existing files
+    #[arg(long)]
+    pub force: bool,
+    /// number of threads to decrypt chunks in parallel
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u16).range(1..))]
+    pub threads: u16,
+    #[clap(flatten)]
+    pub secret: CfgSecret,
+}
+
+
+#[derive(Args, Debug)]
+pub struct List {
+    /// use file as input instead of stdin
+    #[arg(long)]
+    pub fin: Option<PathBuf>,
+    /// number of threads to decrypt chunks in parallel
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u16).range(1..))]
+    pub threads: u16,
+    #[clap(flatten)]
+    pub secret: CfgSecret,
 }
 
 
@@ -57,10 +244,92 @@ pub struct CfgIo {
     /// use file as storage for salt, nonce, cypher type, hash type, etc.
     #[arg(long)]
     pub fspec: Option<PathBuf>,
+    /// overwrite existing output files
+    #[arg(long)]
+    pub force: bool,
+    /// encrypt file to one with suffix added or decrypt it to one with suffix removed,
+    /// source is removed only after output is verified
+    #[arg(long, conflicts_with_all=["fin", "fout", "fspec"])]
+    pub in_place: Option<PathBuf>,
+    /// suffix of encrypted file in in-place mode
+    #[arg(long, default_value=".crydec")]
+    pub suffix: String,
+}
+
+
+/// Params and key slots of encryption
+#[derive(Args, Debug)]
+pub struct CfgEncrypt {
+    #[arg(long, value_enum, default_value_t=Cipher::XChacha20Poly1305)]
+    pub cipher: Cipher,
+    /// plaintext chunk size in bytes, K and M suffixes stand for KiB and MiB
+    #[arg(long, default_value="64K", value_parser=parse_chunk_size)]
+    pub chunk_size: u32,
+    #[clap(flatten)]
+    pub hash: CfgHash,
+    #[clap(flatten)]
+    pub rand: CfgRand,
+    /// number of threads to encrypt chunks in parallel
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u16).range(1..))]
+    pub threads: u16,
+    /// add key slot opened by 32 bytes of key file, may be repeated
+    #[arg(long)]
+    pub key_file: Vec<PathBuf>,
+    /// protect key file slots by password too, key file is used as a second factor
+    #[arg(long, requires="key_file")]
+    pub with_password: bool,
+    /// add key slot opened by identity of public key made by keygen, may be repeated
+    #[arg(long)]
+    pub recipient: Vec<Recipient>,
+    /// number of key slots opened by password, 1 if no key file or recipient is set, 0 otherwise
+    #[arg(long)]
+    pub password_slots: Option<u8>,
+    /// (insecure) password as cli argument, may be repeated to be used by key slots in order
+    #[arg(long)]
+    pub pwd_cli: Vec<String>,
+    /// password from environment variable, may be repeated to be used by key slots in order
+    #[arg(long, conflicts_with="pwd_cli")]
+    pub pwd_env: Vec<String>,
+    /// password read up to the end of open file descriptor, e.g. 3 given `3<file`, may be repeated
+    #[arg(long, conflicts_with_all=["pwd_cli", "pwd_env"])]
+    pub pwd_fd: Vec<i32>,
+    /// password read from file, may be repeated to be used by key slots in order
+    #[arg(long, conflicts_with_all=["pwd_cli", "pwd_env", "pwd_fd"])]
+    pub pwd_file: Vec<PathBuf>,
+    /// password printed to stdout by shell command, e.g. "pass show backups/key", may be repeated
+    #[arg(long, conflicts_with_all=["pwd_cli", "pwd_env", "pwd_fd", "pwd_file"])]
+    pub pwd_cmd: Vec<String>,
+}
+
+impl CfgEncrypt {
+    /// Password sources of key slots in order, only one kind of them may be set
+    pub fn pwd_sources(&self) -> Vec<PwdSource> {
+        self.pwd_cli.iter().cloned().map(PwdSource::Cli)
+            .chain(self.pwd_env.iter().cloned().map(PwdSource::Env))
+            .chain(self.pwd_fd.iter().copied().map(PwdSource::Fd))
+            .chain(self.pwd_file.iter().cloned().map(PwdSource::File))
+            .chain(self.pwd_cmd.iter().cloned().map(PwdSource::Cmd))
+            .collect()
+    }
 }
 
 
+/// Recursive mode processing every file of directory tree
 #[derive(Args, Debug)]
+pub struct CfgTree {
+    /// process every file of directory recursively, output tree is written to --out-dir
+    #[arg(long, requires="out_dir", conflicts_with_all=["fin", "fout", "fspec", "in_place"])]
+    pub recursive: Option<PathBuf>,
+    /// directory to write output tree to in recursive mode
+    #[arg(long, requires="recursive")]
+    pub out_dir: Option<PathBuf>,
+    /// encrypted files are stored under random names with their paths encrypted inside
+    #[arg(long, requires="recursive")]
+    pub hide_names: bool,
+}
+
+
+#[derive(Args, Clone, Debug)]
 pub struct CfgHash {
     #[arg(long, value_enum, default_value_t=ArgonVariant::Argon2id)]
     pub hash_var: ArgonVariant,
@@ -77,6 +346,82 @@ pub struct CfgHash {
     pub time: u32,
 }
 
+impl From<CfgHash> for HashParams {
+    fn from(cfg: CfgHash) -> Self {
+        Self {
+            hash_var: cfg.hash_var,
+            hash_ver: cfg.hash_ver,
+            lanes: cfg.lanes,
+            memory: cfg.memory,
+            time: cfg.time,
+        }
+    }
+}
+
+
+/// Secret to open one of key slots, password is asked for if key slots require it
+#[derive(Args, Debug)]
+pub struct CfgSecret {
+    /// key file to open key slot made for it
+    #[arg(long)]
+    pub key_file: Option<PathBuf>,
+    /// identity file made by keygen to open key slot made for its public key
+    #[arg(long, conflicts_with="key_file")]
+    pub identity: Option<PathBuf>,
+    /// (insecure) password as cli argument
+    #[arg(long)]
+    pub pwd_cli: Option<String>,
+    /// password from environment variable
+    #[arg(long, conflicts_with="pwd_cli")]
+    pub pwd_env: Option<String>,
+    /// password read up to the end of open file descriptor, e.g. 3 given `3<file`
+    #[arg(long, conflicts_with_all=["pwd_cli", "pwd_env"])]
+    pub pwd_fd: Option<i32>,
+    /// password read from file
+    #[arg(long, conflicts_with_all=["pwd_cli", "pwd_env", "pwd_fd"])]
+    pub pwd_file: Option<PathBuf>,
+    /// password printed to stdout by shell command, e.g. "pass show backups/key"
+    #[arg(long, conflicts_with_all=["pwd_cli", "pwd_env", "pwd_fd", "pwd_file"])]
+    pub pwd_cmd: Option<String>,
+}
+
+impl CfgSecret {
+    pub fn pwd_source(&self) -> Option<PwdSource> {
+        make_pwd_source(&self.pwd_cli, &self.pwd_env, self.pwd_fd, &self.pwd_file, &self.pwd_cmd)
+    }
+}
+
+
+/// Argon2 params of new key slot, params of the old one are kept if not set
+#[derive(Args, Debug)]
+pub struct CfgRehash {
+    #[arg(long, value_enum)]
+    pub hash_var: Option<ArgonVariant>,
+    #[arg(long, value_enum)]
+    pub hash_ver: Option<ArgonVersion>,
+    /// argon2 degree of parallelism of new key slot
+    #[arg(long)]
+    pub lanes: Option<u32>,
+    /// argon2 memory cost in kibibytes of new key slot
+    #[arg(long)]
+    pub memory: Option<u32>,
+    /// argon2 number of rounds to use of new key slot
+    #[arg(long)]
+    pub time: Option<u32>,
+}
+
+impl CfgRehash {
+    pub fn apply(&self, hash: &HashParams) -> HashParams {
+        HashParams {
+            hash_var: self.hash_var.unwrap_or(hash.hash_var),
+            hash_ver: self.hash_ver.unwrap_or(hash.hash_ver),
+            lanes: self.lanes.unwrap_or(hash.lanes),
+            memory: self.memory.unwrap_or(hash.memory),
+            time: self.time.unwrap_or(hash.time),
+        }
+    }
+}
+
 
 #[derive(Args, Debug)]
 pub struct CfgRand {
@@ -95,100 +440,29 @@ pub struct CfgRand {
 }
 
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-pub enum ArgonVariant {
-    Argon2i,
-    Argon2d,
-    Argon2id,
-}
-
-impl ArgonVariant {
-    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
-        let res = match type_id {
-            0 => Self::Argon2i,
-            1 => Self::Argon2d,
-            2 => Self::Argon2id,
-            _ => return Err(Error::make_spec("unable to define hash variant")),
-        };
-        Ok(res)
-    }
-
-    pub fn get_type_id(&self) -> u8 {
-        match self {
-            Self::Argon2i => 0,
-            Self::Argon2d => 1,
-            Self::Argon2id => 2,
-        }
-    }
+/// Password source of arguments, only one of them may be set
+fn make_pwd_source(
+    cli: &Option<String>, env: &Option<String>, fd: Option<i32>, file: &Option<PathBuf>, cmd: &Option<String>,
+) -> Option<PwdSource> {
+    cli.clone().map(PwdSource::Cli)
+        .or_else(|| env.clone().map(PwdSource::Env))
+        .or_else(|| fd.map(PwdSource::Fd))
+        .or_else(|| file.clone().map(PwdSource::File))
+        .or_else(|| cmd.clone().map(PwdSource::Cmd))
 }
 
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-pub enum ArgonVersion {
-    Ver10,
-    Ver13,
-}
-
-impl ArgonVersion {
-    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
-        let res = match type_id {
-            0 => Self::Ver10,
-            1 => Self::Ver13,
-            _ => return Err(Error::make_spec("unable to define hash version")),
-        };
-        Ok(res)
-    }
-
-    pub fn get_type_id(&self) -> u8 {
-        match self {
-            Self::Ver10 => 0,
-            Self::Ver13 => 1,
-        }
+fn parse_chunk_size(arg: &str) -> Result<u32, String> {
+    let (digits, multiplier) = match arg.char_indices().last() {
+        Some((idx, 'k' | 'K')) => (&arg[..idx], 1024),
+        Some((idx, 'm' | 'M')) => (&arg[..idx], 1024 * 1024),
+        _ => (arg, 1),
+    };
+    let size = digits.parse::<u32>().ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("unable to parse chunk size: {}", arg))?;
+    if !(CHUNK_SIZE_MIN..=CHUNK_SIZE_MAX).contains(&size) {
+        return Err(format!("chunk size should be from {}K to {}M", CHUNK_SIZE_MIN / 1024, CHUNK_SIZE_MAX / 1024 / 1024));
     }
+    Ok(size)
 }
-
-
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-#[cfg_attr(test, derive(strum::EnumIter))]
-pub enum Cipher {
-    XChacha20Poly1305,
-    XChacha12Poly1305,
-    XChacha8Poly1305,
-    Chacha20Poly1305,
-    Chacha12Poly1305,
-    Chacha8Poly1305,
-    Aes256Gcm,
-    Aes128Gcm,
-}
-
-impl Cipher {
-    /// Returns cipher type by type_id
-    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
-        let res = match type_id {
-            0 => Self::XChacha20Poly1305,
-            1 => Self::XChacha12Poly1305,
-            2 => Self::XChacha8Poly1305,
-            3 => Self::Chacha20Poly1305,
-            4 => Self::Chacha12Poly1305,
-            5 => Self::Chacha8Poly1305,
-            6 => Self::Aes256Gcm,
-            7 => Self::Aes128Gcm,
-            _ => return Err(Error::make_spec("unable to define cipher type")),
-        };
-        Ok(res)
-    }
-
-    /// Returns (type_id, key_size, nonce_size) 
-    pub fn get_spec(&self) -> (u8, u32, usize) {
-        match self {
-            Self::XChacha20Poly1305 => (0, 32, 24),
-            Self::XChacha12Poly1305 => (1, 32, 24),
-            Self::XChacha8Poly1305 => (2, 32, 24),
-            Self::Chacha20Poly1305 => (3, 32, 12),
-            Self::Chacha12Poly1305 => (4, 32, 12),
-            Self::Chacha8Poly1305 => (5, 32, 12),
-            Self::Aes256Gcm => (6, 32, 12),
-            Self::Aes128Gcm => (7, 16, 12),
-        }
-    }
-}
\ No newline at end of file
@@ -1,129 +1,573 @@
-use std::fs::File;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write, copy, stdout, Error as IoError, ErrorKind as IoErrorKind, Result as IoResult},
+    path::Path,
+};
 
-use rand::{RngCore, rngs::OsRng};
+use clap::ValueEnum;
 use zeroize::Zeroizing;
 
-use crate::{
-    hash::make_key,
+use crydec::{
     cipher,
-    cli::{CfgRand, CfgIo, Cipher as CipherType, CfgHash, ArgonVariant, ArgonVersion, Cipher},
-    io::{Input, Output, make_inout, make_control},
     error::Error,
+    header::{Header, KeySource, Slot},
+    io::Input,
+    Params, Secret, Encryptor, Decryptor, Identity,
+};
+
+use crate::{
+    cli::{Encrypt, Decrypt, Verify, Cat, Rekey, Inspect, Keygen, CfgIo, CfgEncrypt, CfgSecret},
+    control::{Control, make_control, prefetch, read_source, read_sources},
+    files::{
+        add_suffix, strip_suffix, remove_file_secure, write_identity,
+        make_inout, make_input, make_output, check_distinct, read_key_file, read_identity,
+    },
+    json::Json,
+    tree,
 };
 
 
-pub fn encrypt(cipher: Cipher, cfg_io: CfgIo, cfg_pwd: Option<String>, cfg_hash: CfgHash, cfg_rand: CfgRand) -> Result<(), Error> {
+/// Amount of first data to wait for before any TTY input
+const PREFETCH_LEN: usize = cipher::CHUNK_SIZE_MIN as usize;
+
+
+pub fn encrypt(cfg: Encrypt) -> Result<(), Error> {
+    if cfg.tree.recursive.is_some() {
+        return tree::encrypt(cfg);
+    }
+    if cfg.append {
+        return append(cfg);
+    }
+
     // IO preparing: input stream, output stream, spec stream
-    let (mut input, mut output) = make_inout(cfg_io.fin, cfg_io.fout)?;
-    let mut spec_stream: Output;
-    let spec = if let Some(path) = cfg_io.fspec {
-        spec_stream = Output::new( Box::new( File::create(path).map_err(Error::make_io)? ) );
-        &mut spec_stream
-    } else {
-        &mut output
+    check_spec(&cfg.io)?;
+    let (path_in, path_out) = match &cfg.io.in_place {
+        Some(path) => (Some(path.clone()), Some(add_suffix(path, &cfg.io.suffix)?)),
+        None => (cfg.io.fin.clone(), cfg.io.fout.clone()),
     };
+    // Passwords given by arguments are read before any file is opened
+    let stdin_is_input = path_in.is_none();
+    let passwords = read_sources(cfg.enc.pwd_sources(), stdin_is_input)?;
+    let (input, mut output) = make_inout(path_in, path_out.clone(), cfg.io.force)?;
+    let spec = cfg.io.fspec.clone().map(|path| make_output(Some(path), cfg.io.force)).transpose()?;
+    if let Some(path) = &cfg.io.in_place {
+        output.copy_metadata(path)?;
+    }
 
     // Control to extract some arguments
-    let ctrl = make_control();
+    let ctrl = make_control(stdin_is_input);
 
     // Have to wait for first data before any TTY input to implement multiple encryption using unix pipes
-    let mut buf = [0u8; cipher::BUFFER_LEN_ENC];
-    let nread = input.read(&mut buf)?;
+    let input = prefetch(input, PREFETCH_LEN)?;
+
+    let secrets = make_secrets(&ctrl, &cfg.enc, passwords)?;
+    // In-place mode verifies output by decryption, so one of secrets has to be able to decrypt
+    let secret_verify = match &cfg.io.in_place {
+        Some(_) => Some(secrets.iter().find(|secret| !matches!(secret, Secret::Recipient(_))).cloned()
+            .ok_or_else(|| Error::make_arg("in-place mode requires password or key file slot to verify output"))?),
+        None => None,
+    };
+    let params = make_params(&ctrl, &cfg.enc)?;
+    let encryptor = make_encryptor(params, secrets, cfg.enc.threads)?;
+
+    // Outputs appear at their paths only when everything is written
+    if let Some(mut spec) = spec {
+        encryptor.encrypt_detached(input, &mut output, &mut spec)?;
+        spec.commit()?;
+    } else {
+        encryptor.encrypt(input, &mut output)?;
+    }
+    output.commit()?;
+
+    // Source is removed only if output decrypts back to it, decryption reuses keys derived by encryption
+    if let (Some(source), Some(target), Some(secret)) = (cfg.io.in_place, path_out, secret_verify) {
+        remove_verified(&source, &target, &encryptor.decryptor(secret))?;
+    }
+    Ok(())
+}
+
+
+/// Removes source securely if target decrypts back to it, source is kept otherwise
+fn remove_verified(source: &Path, target: &Path, decryptor: &Decryptor) -> Result<(), Error> {
+    let mut sink = CompareSink::new(File::open(source).map_err(Error::make_io)?);
+    decryptor.decrypt(File::open(target).map_err(Error::make_io)?, &mut sink)?;
+    sink.finish()?;
+    remove_file_secure(source)
+}
+
+
+/// Encrypts input as a new segment of output file, spec of existing file is kept
+fn append(cfg: Encrypt) -> Result<(), Error> {
+    let path = cfg.io.fout.clone().expect("append mode should have output file");
+    if let Some(fin) = &cfg.io.fin {
+        check_distinct(fin, &path)?;
+    }
+    let passwords = read_sources(cfg.enc.pwd_sources(), cfg.io.fin.is_none())?;
+    let input = make_input(cfg.io.fin.clone())?;
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(Error::make_io)?;
+
+    // Have to wait for first data before any TTY input to implement multiple encryption using unix pipes
+    let ctrl = make_control(cfg.io.fin.is_none());
+    let input = prefetch(input, PREFETCH_LEN)?;
+
+    // Secrets make key slots of new file or open key slot of existing one
+    let secrets = make_secrets(&ctrl, &cfg.enc, passwords)?;
+    let params = make_params(&ctrl, &cfg.enc)?;
+    let encryptor = make_encryptor(params, secrets, cfg.enc.threads)?;
+    encryptor.append(input, &mut file)
+}
+
+
+/// Makes one secret per key slot: key files, recipients, then passwords. Passwords read from
+/// their sources are used in order, the rest are asked for
+pub fn make_secrets(ctrl: &Control, cfg: &CfgEncrypt, passwords: Vec<Zeroizing<String>>) -> Result<Vec<Secret>, Error> {
+    let mut passwords = passwords.into_iter();
+    let mut secrets = Vec::new();
+    for path in &cfg.key_file {
+        let key = read_key_file(path.clone())?;
+        if cfg.with_password {
+            let password = ctrl.prompt(&format!("password for {}", path.display()), passwords.next())?;
+            secrets.push(Secret::PasswordKeyFile(password, key));
+        } else {
+            secrets.push(Secret::KeyFile(key));
+        }
+    }
+    secrets.extend(cfg.recipient.iter().copied().map(Secret::Recipient));
+    let password_slots = cfg.password_slots.unwrap_or(if secrets.is_empty() { 1 } else { 0 });
+    for idx in 1..=password_slots {
+        let name = if password_slots == 1 { "password".to_owned() } else { format!("password {}", idx) };
+        secrets.push(Secret::Password( ctrl.prompt(&name, passwords.next())? ));
+    }
+    Ok(secrets)
+}
+
 
-    // Password preparing
-    let password = ctrl.prompt("password", cfg_pwd)?;
+pub fn make_params(ctrl: &Control, cfg: &CfgEncrypt) -> Result<Params, Error> {
+    let cipher = cfg.cipher;
+    let mut params = Params { cipher, hash: cfg.hash.clone().into(), chunk_size: cfg.chunk_size, ..Default::default() };
 
     // Salt preparing
     let mut salt = Zeroizing::new([0u8; 32]);
-    if let None = ctrl.extract("salt", &mut salt[..], cfg_rand.salt_cli, cfg_rand.salt)? {
-        OsRng.fill_bytes( salt.as_mut());
+    if ctrl.extract("salt", &mut salt[..], cfg.rand.salt_cli.clone(), cfg.rand.salt)?.is_some() {
+        params.salt = Some(salt);
     }
-    
-    let (cipher_id, key_size, mut nonce_size) = cipher.get_spec();
-    nonce_size -= 5; // AEAD requires 5 bytes of nonce to operate
 
     // Nonce preparing
-    let mut nonce_arr = Zeroizing::new([0u8; 19]);
-    let nonce = &mut nonce_arr[..nonce_size];
-    if let None = ctrl.extract("nonce", nonce, cfg_rand.nonce_cli, cfg_rand.nonce)? {
-        OsRng.fill_bytes(nonce);
+    let mut nonce = Zeroizing::new(vec![0u8; Header::nonce_size(cipher)]);
+    if ctrl.extract("nonce", &mut nonce, cfg.rand.nonce_cli.clone(), cfg.rand.nonce)?.is_some() {
+        params.nonce = Some(nonce);
     }
+    Ok(params)
+}
 
-    // Key preparing
-    let key = make_key(&cfg_hash, &password, salt.as_ref(), key_size)?;
 
-    spec.write_u8(cfg_hash.hash_var.get_type_id())?;
-    spec.write_u8(cfg_hash.hash_ver.get_type_id())?;
-    spec.write_u32(cfg_hash.lanes)?;
-    spec.write_u32(cfg_hash.memory)?;
-    spec.write_u32(cfg_hash.time)?;
-    spec.write(salt.as_ref())?;
+pub fn make_encryptor(params: Params, secrets: Vec<Secret>, threads: u16) -> Result<Encryptor, Error> {
+    let mut secrets = secrets.into_iter();
+    let secret = secrets.next().ok_or_else(|| Error::make_arg("at least one key slot is required"))?;
+    Ok(secrets.fold(Encryptor::new(params, secret), Encryptor::with_secret).with_threads(threads.into()))
+}
+
 
-    spec.write_u8(cipher_id)?;
-    spec.write(nonce)?;
+pub fn decrypt(cfg: Decrypt) -> Result<(), Error> {
+    if cfg.tree.recursive.is_some() {
+        return tree::decrypt(cfg);
+    }
+
+    // IO preparing: input stream, output stream, spec stream
+    check_spec(&cfg.io)?;
+    let (path_in, path_out) = match &cfg.io.in_place {
+        Some(path) => (Some(path.clone()), Some(strip_suffix(path, &cfg.io.suffix)?)),
+        None => (cfg.io.fin, cfg.io.fout),
+    };
+    let stdin_is_input = path_in.is_none();
+    let pwd = read_source(cfg.secret.pwd_source(), stdin_is_input)?;
+    let (input, mut output) = make_inout(path_in, path_out, cfg.io.force)?;
+    let spec = cfg.io.fspec.map(File::open).transpose().map_err(Error::make_io)?;
+    if let Some(path) = &cfg.io.in_place {
+        output.copy_metadata(path)?;
+    }
 
-    cipher::encrypt(cipher, &key[..], nonce, &mut input, &mut output, buf, nread)?;
+    // Plaintext appears at its path only if it is decrypted and authenticated completely
+    decrypt_to(input, stdin_is_input, spec, cfg.threads, cfg.secret, pwd, &mut output)?;
+    output.commit()?;
 
+    // Every chunk is authenticated by decryption, so committed output is verified
+    if let Some(source) = cfg.io.in_place {
+        remove_file_secure(&source)?;
+    }
     Ok(())
-    
 }
 
 
-pub fn decrypt(cfg_io: CfgIo, cfg_pwd: Option<String>) -> Result<(), Error> {
-    // IO preparing: input stream, output stream, spec stream
-    let (mut input, mut output) = make_inout(cfg_io.fin, cfg_io.fout)?;
-    let mut spec_stream: Input;
-    let spec = if let Some(path) = cfg_io.fspec {
-        spec_stream = Input::new( Box::new( File::open(path).map_err(Error::make_io)? ) );
-        &mut spec_stream
+pub fn verify(cfg: Verify) -> Result<(), Error> {
+    // IO preparing: input stream, spec stream, plaintext is only counted
+    let stdin_is_input = cfg.fin.is_none();
+    let pwd = read_source(cfg.secret.pwd_source(), stdin_is_input)?;
+    let input = make_input(cfg.fin)?;
+    let spec = cfg.fspec.map(File::open).transpose().map_err(Error::make_io)?;
+    let mut sink = CountingSink { count: 0 };
+
+    decrypt_to(input, stdin_is_input, spec, cfg.threads, cfg.secret, pwd, &mut sink)?;
+    writeln!(stdout(), "ok: {} bytes of plaintext verified", sink.count).map_err(Error::make_io)
+}
+
+
+pub fn cat(cfg: Cat) -> Result<(), Error> {
+    // IO preparing: seekable input, spec is read from input header unless it is detached
+    let pwd = read_source(cfg.secret.pwd_source(), false)?;
+    let mut input = File::open(&cfg.fin).map_err(Error::make_io)?;
+    let header = if let Some(fspec) = &cfg.fspec {
+        check_distinct(fspec, &cfg.fin)?;
+        Header::read(&mut Input::new( Box::new( File::open(fspec).map_err(Error::make_io)? ) ))?
     } else {
-        &mut input
+        Header::read(&mut Input::new( Box::new(&mut input) ))?
     };
 
-    let hash_variant = ArgonVariant::from_type_id( spec.read_u8()? )?;
-    let hash_version = ArgonVersion::from_type_id( spec.read_u8()? )?;
-    let hash_lanes = spec.read_u32()?;
-    let hash_memory = spec.read_u32()?;
-    let hash_time = spec.read_u32()?;
-
-    let cfg_hash = CfgHash {
-        hash_var: hash_variant,
-        hash_ver: hash_version,
-        lanes: hash_lanes,
-        memory: hash_memory,
-        time: hash_time,
+    let ctrl = make_control(false);
+    let secret = choose_secret(&ctrl, &header, cfg.secret, pwd)?;
+    let mut reader = Decryptor::new(secret).seekable_with_header(&header, input)?;
+
+    // Only chunks covering the range are read and decrypted
+    let start = if cfg.offset < 0 {
+        reader.size().saturating_sub(cfg.offset.unsigned_abs())
+    } else {
+        cfg.offset as u64
     };
+    reader.seek(SeekFrom::Start(start)).map_err(Error::make_io)?;
+    let mut reader = reader.take(cfg.length.unwrap_or(u64::MAX));
+    copy(&mut reader, &mut stdout().lock()).map_err(Error::from)?;
+    Ok(())
+}
 
-    // Salt preparing
-    let mut salt = Zeroizing::new([0u8; 32]);
-    spec.read_exact(salt.as_mut())?;
 
-    let cipher_type = CipherType::from_type_id( spec.read_u8()? )?;
-    let (_, key_size, mut nonce_size) = cipher_type.get_spec();
-    nonce_size -= 5; // AEAD requires 5 bytes of nonce to operate
+pub fn decrypt_to(
+    input: Input<'static>, stdin_is_input: bool, spec: Option<File>, threads: u16, cfg: CfgSecret, pwd: Option<Zeroizing<String>>, writer: impl Write,
+) -> Result<(), Error> {
+    // Have to wait for first data before any TTY input to implement multiple encryption using unix pipes
+    let mut input = Input::new( Box::new( prefetch(input, PREFETCH_LEN)? ) );
 
-    // Nonce preparing
-    let mut nonce_arr = Zeroizing::new([0u8; 19]);
-    let nonce = {
-        let nonce_slice = &mut nonce_arr[..nonce_size];
-        spec.read_exact(nonce_slice)?;
-        nonce_slice
+    // Spec defines secret to ask for
+    let header = if let Some(spec) = spec {
+        Header::read(&mut Input::new( Box::new(spec) ))?
+    } else {
+        Header::read(&mut input)?
     };
 
-    // Have to wait for first data before any TTY input to implement multiple encryption using unix pipes
-    let mut buf = [0u8; cipher::BUFFER_LEN_DEC];
-    let nread = input.read(&mut buf)?;
-
     // Control to extract password
-    let ctrl = make_control();
+    let ctrl = make_control(stdin_is_input);
+
+    // Secret preparing: key slots define what to ask for
+    let secret = choose_secret(&ctrl, &header, cfg, pwd)?;
+
+    let decryptor = Decryptor::new(secret).with_threads(threads.into());
+    decryptor.decrypt_with_header(&header, input.into_inner(), writer)
+}
+
+
+pub fn rekey(cfg: Rekey) -> Result<(), Error> {
+    let pwd = read_source(cfg.secret.pwd_source(), false)?;
+    let new_pwd = read_source(cfg.new_pwd_source(), false)?;
+    let path = cfg.fspec.or(cfg.fin).expect("either --fin or --fspec is required");
+    let mut file = OpenOptions::new().read(true).write(true).open(&path).map_err(Error::make_io)?;
+    let mut header = Header::read(&mut Input::new( Box::new(&mut file) ))?;
+    let spec_len = file.stream_position().map_err(Error::make_io)?;
+
+    let ctrl = make_control(false);
+    let key_file = cfg.secret.key_file.clone();
+    let old = choose_secret(&ctrl, &header, cfg.secret, pwd)?;
+    let new = match old.key_source() {
+        KeySource::Password => {
+            Secret::Password(prompt_new_password(&ctrl, new_pwd)?)
+        },
+        KeySource::KeyFile => {
+            let path = cfg.new_key_file.ok_or_else(|| Error::make_arg("new key file is required, use --new-key-file"))?;
+            Secret::KeyFile(read_key_file(path)?)
+        },
+        KeySource::PasswordKeyFile => {
+            let path = cfg.new_key_file.or(key_file).expect("key file is required to open key slot");
+            let key = read_key_file(path)?;
+            Secret::PasswordKeyFile(prompt_new_password(&ctrl, new_pwd)?, key)
+        },
+        KeySource::Recipient => {
+            let recipient = cfg.new_recipient.ok_or_else(|| Error::make_arg("new public key is required, use --new-recipient"))?;
+            Secret::Recipient(recipient)
+        },
+    };
+    crydec::rekey(&mut header, &old, &new, |hash| cfg.hash.apply(hash))?;
+
+    // Only spec is rewritten in place, encrypted data following it is not touched. Spec is written
+    // by a single write and synced, crash in the middle of it may leave key slot damaged
+    let spec = header.to_bytes();
+    if spec.len() as u64 != spec_len {
+        return Err(Error::make_spec("new spec differs in size from the old one, unable to rewrite it in place"));
+    }
+    file.seek(SeekFrom::Start(0)).map_err(Error::make_io)?;
+    file.write_all(&spec).map_err(Error::make_io)?;
+    file.sync_all().map_err(Error::make_io)
+}
+
+
+pub fn inspect(cfg: Inspect) -> Result<(), Error> {
+    let header = Header::read(&mut make_input( cfg.fspec.or(cfg.fin) )?)?;
+
+    let text = if cfg.json {
+        format!("{}\n", spec_json(&header))
+    } else {
+        spec_text(&header)
+    };
+    stdout().write_all(text.as_bytes()).map_err(Error::make_io)
+}
+
 
-    // Password preparing
-    let password = ctrl.prompt("password", cfg_pwd)?;
+pub fn keygen(cfg: Keygen) -> Result<(), Error> {
+    // Existing public key file is refused before identity is written
+    let mut output = make_output(cfg.public, cfg.force)?;
+    let identity = Identity::generate();
+    write_identity(cfg.identity, &identity)?;
 
-    // Key preparing
-    let key = make_key(&cfg_hash, &password, salt.as_ref(), key_size)?;
+    let recipient = format!("{}\n", identity.to_recipient());
+    output.write_all(recipient.as_bytes()).map_err(Error::make_io)?;
+    output.commit()
+}
 
-    cipher::decrypt(cipher_type, &key[..], nonce, &mut input, &mut output, buf, nread)?;
 
+/// Spec file can be neither input nor output of data
+fn check_spec(cfg: &CfgIo) -> Result<(), Error> {
+    if let Some(fspec) = &cfg.fspec {
+        for path in cfg.fin.iter().chain(&cfg.fout) {
+            check_distinct(fspec, path)?;
+        }
+    }
     Ok(())
 }
+
+
+/// Chooses secret to open one of key slots by given arguments, asks for password if it is needed and not read yet
+pub fn choose_secret(ctrl: &Control, header: &Header, cfg: CfgSecret, pwd: Option<Zeroizing<String>>) -> Result<Secret, Error> {
+    let key_sources = header.slots.iter().map(Slot::key_source).collect::<Vec<_>>();
+    let has_slot = |key_source| key_sources.contains(&key_source);
+    let secret = match (cfg.identity, cfg.key_file) {
+        (Some(path), _) if has_slot(KeySource::Recipient) => {
+            Secret::Identity(read_identity(path)?)
+        },
+        (_, Some(path)) if has_slot(KeySource::KeyFile) => {
+            Secret::KeyFile(read_key_file(path)?)
+        },
+        (_, Some(path)) if has_slot(KeySource::PasswordKeyFile) => {
+            let key = read_key_file(path)?;
+            Secret::PasswordKeyFile(ctrl.prompt("password", pwd)?, key)
+        },
+        _ if has_slot(KeySource::Password) => {
+            Secret::Password(ctrl.prompt("password", pwd)?)
+        },
+        _ if has_slot(KeySource::Recipient) => {
+            return Err(Error::make_arg("identity file is required, use --identity"));
+        },
+        _ => return Err(Error::make_arg("key file is required, use --key-file")),
+    };
+    Ok(secret)
+}
+
+
+/// Asks for new password twice unless it is read from source given by argument
+fn prompt_new_password(ctrl: &Control, password: Option<Zeroizing<String>>) -> Result<Zeroizing<String>, Error> {
+    if password.is_some() {
+        return ctrl.prompt("new password", password);
+    }
+    let password = ctrl.prompt("new password", None)?;
+    if password != ctrl.prompt("repeat new password", None)? {
+        return Err(Error::make_arg("new passwords do not match"));
+    }
+    Ok(password)
+}
+
+
+/// Discards written data counting its size
+struct CountingSink {
+    count: u64,
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+
+/// Compares written data with content of reader
+struct CompareSink<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    is_same: bool,
+}
+
+impl <R: Read> CompareSink<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, buf: Vec::new(), is_same: true }
+    }
+
+    /// Fails if written data differs from reader content or is shorter than it
+    fn finish(mut self) -> Result<(), Error> {
+        let mut byte = [0u8; 1];
+        if !self.is_same || self.reader.read(&mut byte).map_err(Error::make_io)? != 0 {
+            return Err(Error::make_io(IoError::new(IoErrorKind::InvalidData, "output does not match source, source is kept")));
+        }
+        Ok(())
+    }
+}
+
+impl <R: Read> Write for CompareSink<R> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.is_same {
+            self.buf.resize(buf.len(), 0);
+            match self.reader.read_exact(&mut self.buf) {
+                Ok(()) => self.is_same = self.buf == buf,
+                Err(err) if err.kind() == IoErrorKind::UnexpectedEof => self.is_same = false,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+
+fn spec_text(header: &Header) -> String {
+    let mut lines = vec![
+        format!("version: {}", header.version()),
+        format!("cipher: {}", value_name(header.cipher)),
+        format!("nonce: {}", hex::encode(&header.nonce[..])),
+        format!("chunk size: {}", header.chunk_size),
+    ];
+    for (idx, slot) in header.slots.iter().enumerate() {
+        lines.push(format!("slot {}: {}", idx, slot.key_source().get_name()));
+        match slot {
+            Slot::Password { hash, salt, .. } | Slot::PasswordKeyFile { hash, salt, .. } => {
+                lines.push(format!("  hash: {} {}", value_name(hash.hash_var), value_name(hash.hash_ver)));
+                lines.push(format!("  lanes: {}", hash.lanes));
+                lines.push(format!("  memory: {} KiB", hash.memory));
+                lines.push(format!("  time: {}", hash.time));
+                lines.push(format!("  salt: {}", hex::encode(salt.as_ref())));
+            },
+            Slot::KeyFile { .. } => {},
+            Slot::Recipient { ephemeral, .. } => {
+                lines.push(format!("  ephemeral key: {}", hex::encode(ephemeral)));
+            },
+        }
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+
+fn spec_json(header: &Header) -> Json {
+    let slots = header.slots.iter().map(|slot| {
+        let mut fields = vec![("key_source", Json::Str( slot.key_source().get_name().to_owned() ))];
+        match slot {
+            Slot::Password { hash, salt, .. } | Slot::PasswordKeyFile { hash, salt, .. } => {
+                fields.push(("hash_var", Json::Str( value_name(hash.hash_var) )));
+                fields.push(("hash_ver", Json::Str( value_name(hash.hash_ver) )));
+                fields.push(("lanes", Json::Num( hash.lanes.into() )));
+                fields.push(("memory", Json::Num( hash.memory.into() )));
+                fields.push(("time", Json::Num( hash.time.into() )));
+                fields.push(("salt", Json::Str( hex::encode(salt.as_ref()) )));
+            },
+            Slot::KeyFile { .. } => {},
+            Slot::Recipient { ephemeral, .. } => {
+                fields.push(("ephemeral", Json::Str( hex::encode(ephemeral) )));
+            },
+        }
+        Json::Obj(fields)
+    });
+    Json::Obj(vec![
+        ("version", Json::Num( header.version().into() )),
+        ("cipher", Json::Str( value_name(header.cipher) )),
+        ("nonce", Json::Str( hex::encode(&header.nonce[..]) )),
+        ("chunk_size", Json::Num( header.chunk_size.into() )),
+        ("slots", Json::Arr( slots.collect() )),
+    ])
+}
+
+
+/// Name of value as it is used in cli arguments
+fn value_name(value: impl ValueEnum) -> String {
+    value.to_possible_value().expect("value should not be skipped").get_name().to_owned()
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::PathBuf};
+    use rand::{RngCore, rngs::OsRng};
+    use zeroize::Zeroizing;
+    use crydec::{error::Error, Params, Secret, Encryptor};
+    use super::remove_verified;
+
+
+    #[test]
+    fn remove_verified_matching() {
+        let dir = make_dir();
+        let (source, target) = (dir.join("src"), dir.join("src.crydec"));
+        std::fs::write(&source, make_data(5000)).unwrap();
+        let secret = Secret::KeyFile(Zeroizing::new(make_data(32)));
+        let encryptor = Encryptor::new(Params::default(), secret.clone());
+        encryptor.encrypt(File::open(&source).unwrap(), File::create(&target).unwrap()).unwrap();
+
+        remove_verified(&source, &target, &encryptor.decryptor(secret)).unwrap();
+        assert!(!source.exists());
+        assert!(target.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn remove_verified_keeps_source() {
+        let dir = make_dir();
+        let (source, target) = (dir.join("src"), dir.join("src.crydec"));
+        let data = make_data(5000);
+        std::fs::write(&source, &data).unwrap();
+        let secret = Secret::KeyFile(Zeroizing::new(make_data(32)));
+        let encryptor = Encryptor::new(Params::default(), secret.clone());
+        encryptor.encrypt(File::open(&source).unwrap(), File::create(&target).unwrap()).unwrap();
+
+        // Source changed after encryption, output is shorter than source, output fails authentication
+        let changed = [make_data(5000), data[..4000].to_vec()];
+        for content in changed {
+            std::fs::write(&source, &content).unwrap();
+            let res = remove_verified(&source, &target, &encryptor.decryptor(secret.clone()));
+            assert!(matches!(res, Err(Error::Io(_))));
+            assert_eq!(content, std::fs::read(&source).unwrap());
+        }
+        std::fs::write(&source, &data).unwrap();
+        let mut enc = std::fs::read(&target).unwrap();
+        *enc.last_mut().unwrap() ^= 1;
+        std::fs::write(&target, enc).unwrap();
+        let res = remove_verified(&source, &target, &encryptor.decryptor(secret));
+        assert!(matches!(res, Err(Error::Truncated(_))));
+        assert_eq!(data, std::fs::read(&source).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    fn make_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crydec-test-{:016x}", OsRng.next_u64()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+
+    fn make_data(size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+}
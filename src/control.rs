@@ -0,0 +1,474 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Cursor, Result as IoResult},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use zeroize::Zeroizing;
+
+use crydec::{error::Error, io::Input};
+
+
+/// Waits for the first data of input, returns reader yielding the same data from the start
+pub fn prefetch<'a>(mut input: Input<'a>, size: usize) -> Result<impl Read + 'a, Error> {
+    let mut buf = vec![0u8; size];
+    let nread = input.read(&mut buf)?;
+    buf.truncate(nread);
+    Ok(Cursor::new(buf).chain(input.into_inner()))
+}
+
+/// Source of password given by arguments instead of TTY prompt
+#[derive(Clone, Debug)]
+pub enum PwdSource {
+    /// (insecure) password itself
+    Cli(String),
+    /// Name of environment variable
+    Env(String),
+    /// Open file descriptor, e.g. pipe, it is read up to its end and left open
+    Fd(i32),
+    File(PathBuf),
+    /// Shell command printing password to its stdout, e.g. of secrets manager
+    Cmd(String),
+}
+
+/// Longest password read from file or file descriptor, so buffer is never reallocated leaving copies behind
+const PASSWORD_SIZE_MAX: usize = 4096;
+
+impl PwdSource {
+    /// Reads password, one trailing newline is stripped. Stdin carrying input data is not a password source
+    pub fn read(&self, stdin_is_input: bool) -> Result<Zeroizing<String>, Error> {
+        let password = match self {
+            Self::Cli(password) => Zeroizing::new(password.clone()),
+            Self::Env(name) => match std::env::var(name) {
+                Ok(password) => Zeroizing::new(password),
+                Err(std::env::VarError::NotPresent) => {
+                    return Err(Error::make_arg(format!("environment variable {} is not set", name)));
+                },
+                Err(std::env::VarError::NotUnicode(_)) => {
+                    return Err(Error::make_arg(format!("environment variable {} should be utf-8", name)));
+                },
+            },
+            Self::Fd(0) if stdin_is_input => {
+                return Err(Error::make_arg("file descriptor 0 is stdin carrying input data, use --fin for data"));
+            },
+            Self::Fd(fd) => read_password(&mut *open_fd(*fd)?)?,
+            Self::File(path) => read_password(&mut File::open(path).map_err(Error::make_io)?)?,
+            Self::Cmd(cmd) => run_password_command(&mut make_shell_command(cmd), stdin_is_input)?,
+        };
+        check_empty(strip_newline(password), &self.to_string())
+    }
+}
+
+impl std::fmt::Display for PwdSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cli(_) => write!(f, "argument"),
+            Self::Env(name) => write!(f, "environment variable {}", name),
+            Self::Fd(fd) => write!(f, "file descriptor {}", fd),
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Cmd(_) => write!(f, "password command"),
+        }
+    }
+}
+
+/// Reads passwords of sources before any file is opened, so descriptor of source is always
+/// inherited one and never a file opened by crydec itself
+pub fn read_sources(sources: Vec<PwdSource>, stdin_is_input: bool) -> Result<Vec<Zeroizing<String>>, Error> {
+    sources.iter().map(|source| source.read(stdin_is_input)).collect()
+}
+
+/// The same as `read_sources` for optional source
+pub fn read_source(source: Option<PwdSource>, stdin_is_input: bool) -> Result<Option<Zeroizing<String>>, Error> {
+    source.map(|source| source.read(stdin_is_input)).transpose()
+}
+
+/// Empty password given without prompt is taken as a mistake, e.g. unset variable or drained descriptor
+fn check_empty(password: Zeroizing<String>, source: &str) -> Result<Zeroizing<String>, Error> {
+    if password.is_empty() {
+        return Err(Error::make_arg(format!("password from {} is empty", source)));
+    }
+    Ok(password)
+}
+
+fn strip_newline(mut password: Zeroizing<String>) -> Zeroizing<String> {
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+    password
+}
+
+fn read_password(reader: &mut impl Read) -> Result<Zeroizing<String>, Error> {
+    let mut bytes = Zeroizing::new(Vec::with_capacity(PASSWORD_SIZE_MAX + 1));
+    reader.take(PASSWORD_SIZE_MAX as u64 + 1).read_to_end(&mut bytes).map_err(Error::make_io)?;
+    if bytes.len() > PASSWORD_SIZE_MAX {
+        return Err(Error::make_arg(format!("password should be at most {} bytes", PASSWORD_SIZE_MAX)));
+    }
+    match String::from_utf8(std::mem::take(&mut *bytes)) {
+        Ok(password) => Ok(Zeroizing::new(password)),
+        Err(err) => {
+            drop(Zeroizing::new(err.into_bytes()));
+            Err(Error::make_arg("password should be utf-8"))
+        },
+    }
+}
+
+/// File of descriptor inherited from parent process, it is not closed on drop
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<std::mem::ManuallyDrop<File>, Error> {
+    use std::os::unix::io::FromRawFd;
+    // Descriptor is checked to be open before it is wrapped, fstat is made through its /dev/fd entry
+    if fd < 0 || std::fs::metadata(format!("/dev/fd/{}", fd)).is_err() {
+        return Err(Error::make_arg(format!("file descriptor {} is not open", fd)));
+    }
+    // Descriptor is only read and never closed
+    Ok(std::mem::ManuallyDrop::new( unsafe { File::from_raw_fd(fd) } ))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<std::mem::ManuallyDrop<File>, Error> {
+    Err(Error::make_arg("password from file descriptor is supported on unix only"))
+}
+
+#[cfg(unix)]
+fn make_shell_command(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(not(unix))]
+fn make_shell_command(cmd: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(cmd);
+    command
+}
+
+/// Runs command reading password from its stdout, stdin and stderr are left to the command,
+/// e.g. to ask for passphrase of secrets manager, unless stdin is data input the command must not consume
+fn run_password_command(command: &mut Command, stdin_is_input: bool) -> Result<Zeroizing<String>, Error> {
+    if stdin_is_input {
+        command.stdin(Stdio::null());
+    }
+    let mut child = command.stdout(Stdio::piped()).spawn().map_err(Error::make_io)?;
+    let mut stdout = child.stdout.take().expect("stdout of command is piped");
+    let password = read_password(&mut stdout);
+    // Command writing too much gets broken pipe instead of waiting for the read forever
+    drop(stdout);
+    let status = child.wait().map_err(Error::make_io)?;
+    if !status.success() {
+        return Err(Error::make_arg(format!("password command failed: {}", status)));
+    }
+    password
+}
+
+
+/// Environment variable naming program to ask for passwords when there is no TTY, like `SSH_ASKPASS`.
+/// Program gets prompt as its argument and prints password to its stdout
+pub const ASKPASS_ENV: &str = "CRYDEC_ASKPASS";
+
+/// Environment variable making askpass program used even with TTY if it is `force`, like `SSH_ASKPASS_REQUIRE`
+pub const ASKPASS_REQUIRE_ENV: &str = "CRYDEC_ASKPASS_REQUIRE";
+
+/// Askpass program gets no stdin if stdin is data input
+pub fn make_control(stdin_is_input: bool) -> Control {
+    let program = std::env::var_os(ASKPASS_ENV).filter(|program| !program.is_empty());
+    let is_forced = std::env::var_os(ASKPASS_REQUIRE_ENV).map_or(false, |value| value == "force");
+    match program {
+        Some(program) if is_forced || !has_tty() => Control::askpass(program, stdin_is_input),
+        _ => Control::new(|req| rpassword::prompt_password(req)),
+    }
+}
+
+/// TTY prompt is possible: rpassword prompts on controlling terminal
+#[cfg(unix)]
+fn has_tty() -> bool {
+    std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty").is_ok()
+}
+
+#[cfg(not(unix))]
+fn has_tty() -> bool {
+    true
+}
+
+
+/// Asks for value by prompt, e.g. on TTY
+type PromptFn = dyn Fn(&str) -> Result<Zeroizing<String>, Error>;
+
+pub struct Control {
+    func: Box<PromptFn>,
+}
+
+impl Control {
+    fn new(func: impl Fn(&str) -> IoResult<String> + 'static) -> Self {
+        Self { func: Box::new(move |req| func(req).map(Zeroizing::new).map_err(Error::make_io)) }
+    }
+
+    /// Asks for passwords by running program with prompt as its argument
+    fn askpass(program: OsString, stdin_is_input: bool) -> Self {
+        Self { func: Box::new(move |req| {
+            let password = run_password_command(Command::new(&program).arg(req), stdin_is_input)?;
+            check_empty(strip_newline(password), "askpass program")
+        }) }
+    }
+
+    /// Returns password read from its source beforehand if it is set, asks for it otherwise
+    pub fn prompt(&self, name: &str, password: Option<Zeroizing<String>>) -> Result<Zeroizing<String>, Error> {
+        if let Some(password) = password {
+            Ok(password)
+        } else {
+            let request = format!("{}: ", name);
+            (self.func)(&request)
+        }
+    }
+
+    pub fn extract(&self, name: &str, slice: &mut [u8], arg: Option<String>, is_tty: bool) -> Result<Option<()>, Error> {
+        let text_opt = if let Some(arg) = arg {
+            Some(Zeroizing::new(arg))
+        } else if is_tty {
+            let request = format!("{}: ", name);
+            Some((self.func)(&request)?)
+        } else {
+            None
+        };
+        if let Some(text) = text_opt {
+            let size = slice.len();
+            let bytes = text.as_bytes();
+            if bytes.len() < size {
+                return Err(Error::make_arg(format!("{} shoud be at least {} bytes", name, size)));
+            }
+            let _ = &slice.copy_from_slice(&bytes[..size]);
+            Ok(Some(()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use rand::{RngCore, rngs::OsRng};
+    use zeroize::Zeroizing;
+    use crydec::error::Error as CrateError;
+    use super::{Control, PwdSource};
+
+
+    #[test]
+    fn control_prompt_arg() {
+        let name: &str = "prompt-arg";
+        const REQ_LEFT: &str = "prompt-arg: ";
+        let arg = Some(Zeroizing::new("imput via argument".to_string()));
+        let resp_left = Zeroizing::new("imput via argument".to_string());
+
+        let control = Control::new(|req| {
+            assert_eq!(REQ_LEFT, req);
+            Ok("imput via TTY".to_string())   
+        });
+        let resp_right = control.prompt(name, arg).unwrap();
+        assert_eq!(resp_left, resp_right);
+    }
+
+
+    #[test]
+    fn control_prompt_noarg() {
+        let name: &str = "prompt-noarg";
+        const REQ_LEFT: &str = "prompt-noarg: ";
+        let arg: Option<Zeroizing<String>> = None;
+        let resp_left = Zeroizing::new("imput via TTY".to_string());
+
+        let control = Control::new(|req| {
+            assert_eq!(REQ_LEFT, req);
+            Ok("imput via TTY".to_string())   
+        });
+        let resp_right = control.prompt(name, arg).unwrap();
+        assert_eq!(resp_left, resp_right);
+    }
+
+
+    #[test]
+    fn pwd_source_env_file() {
+        std::env::set_var("CRYDEC_TEST_PWD_ENV", "env password\n");
+        assert_eq!("env password", &*PwdSource::Env("CRYDEC_TEST_PWD_ENV".to_owned()).read(false).unwrap());
+        let res = PwdSource::Env("CRYDEC_TEST_PWD_MISSING".to_owned()).read(false);
+        assert!(matches!(res, Err(CrateError::Arg(_))));
+
+        // Only one trailing newline is stripped
+        let dir = make_dir();
+        for (content, expected) in [("file\n", "file"), ("file\r\n", "file"), ("file\n\n", "file\n"), ("fi\nle", "fi\nle")] {
+            std::fs::write(dir.join("pwd"), content).unwrap();
+            assert_eq!(expected, &*PwdSource::File(dir.join("pwd")).read(false).unwrap());
+        }
+        std::fs::write(dir.join("pwd"), vec![b'a'; super::PASSWORD_SIZE_MAX + 1]).unwrap();
+        assert!(matches!(PwdSource::File(dir.join("pwd")).read(false), Err(CrateError::Arg(_))));
+        std::fs::write(dir.join("pwd"), [0xff, 0xfe]).unwrap();
+        assert!(matches!(PwdSource::File(dir.join("pwd")).read(false), Err(CrateError::Arg(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn pwd_source_empty() {
+        let dir = make_dir();
+        std::env::set_var("CRYDEC_TEST_PWD_EMPTY", "\n");
+        std::fs::write(dir.join("pwd"), "").unwrap();
+        for source in [
+            PwdSource::Cli(String::new()),
+            PwdSource::Env("CRYDEC_TEST_PWD_EMPTY".to_owned()),
+            PwdSource::File(dir.join("pwd")),
+        ] {
+            assert!(matches!(source.read(false), Err(CrateError::Arg(msg)) if msg.ends_with("is empty")));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn pwd_source_fd() {
+        use std::os::unix::io::AsRawFd;
+        let dir = make_dir();
+        std::fs::write(dir.join("pwd"), "fd password\n").unwrap();
+        let file = std::fs::File::open(dir.join("pwd")).unwrap();
+        assert_eq!("fd password", &*PwdSource::Fd(file.as_raw_fd()).read(false).unwrap());
+        // Descriptor is left open
+        assert!(file.metadata().is_ok());
+        drop(file);
+        // Descriptor which is not open is refused before it is read
+        for fd in [-1, i32::MAX] {
+            assert!(matches!(PwdSource::Fd(fd).read(false), Err(CrateError::Arg(_))));
+        }
+        // Stdin carrying input data is not a password source
+        let res = PwdSource::Fd(0).read(true);
+        assert!(matches!(res, Err(CrateError::Arg(msg)) if msg.contains("stdin")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn pwd_source_cmd() {
+        assert_eq!("cmd password", &*PwdSource::Cmd("echo 'cmd password'".to_owned()).read(false).unwrap());
+        // Command failing or printing too much fails even if something was printed
+        let res = PwdSource::Cmd("echo partial; exit 3".to_owned()).read(false);
+        assert!(matches!(res, Err(CrateError::Arg(_))));
+        let res = PwdSource::Cmd(format!("head -c {} /dev/zero | tr '\\0' a", super::PASSWORD_SIZE_MAX + 1)).read(false);
+        assert!(matches!(res, Err(CrateError::Arg(_))));
+        let res = PwdSource::Cmd("yes".to_owned()).read(false);
+        assert!(matches!(res, Err(CrateError::Arg(_))));
+        let res = PwdSource::Cmd("true".to_owned()).read(false);
+        assert!(matches!(res, Err(CrateError::Arg(_))));
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn control_askpass() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = make_dir();
+        let script = dir.join("askpass.sh");
+        std::fs::write(&script, "#!/bin/sh\nprintf 'askpass for %s\\n' \"$1\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        let control = Control::askpass(script.clone().into_os_string(), false);
+        assert_eq!("askpass for password: ", &*control.prompt("password", None).unwrap());
+        // Source given by arguments is not replaced
+        let arg = Some(Zeroizing::new("imput via argument".to_string()));
+        assert_eq!("imput via argument", &*control.prompt("password", arg).unwrap());
+
+        // Askpass printing nothing, e.g. cancelled, gives no password
+        let empty = dir.join("empty.sh");
+        std::fs::write(&empty, "#!/bin/sh\necho\n").unwrap();
+        std::fs::set_permissions(&empty, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let control = Control::askpass(empty.into_os_string(), false);
+        assert!(matches!(control.prompt("password", None), Err(CrateError::Arg(_))));
+
+        let control = Control::askpass(dir.join("missing").into_os_string(), false);
+        assert!(matches!(control.prompt("password", None), Err(CrateError::Io(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn control_extract_arg() {
+        let name: &str = "extract-arg";
+        const REQ_LEFT: &str = "extract-arg: ";
+        let arg = Some("imput a argument".to_string());
+
+        let data = "imput a argument";
+        let data_left = data.as_bytes();
+        let mut data_right = [0u8; 16];
+
+        let control = Control::new(|req| {
+            assert_eq!(REQ_LEFT, req);
+            Ok("imput via TTY".to_string())   
+        });
+
+        let res = control.extract(name, &mut data_right[..], arg, true).unwrap();
+        assert_eq!(Some(()),res);
+        assert_eq!(data_left, data_right);
+    }
+
+
+    #[test]
+    fn control_extract_noarg() {
+        let name: &str = "extract-arg";
+        const REQ_LEFT: &str = "extract-arg: ";
+        let arg = None;
+
+        let data = "imput via TTY...";
+        let data_left = data.as_bytes();
+        let mut data_right = [0u8; 16];
+
+        let control = Control::new(|req| {
+            assert_eq!(REQ_LEFT, req);
+            Ok("imput via TTY...".to_string())   
+        });
+
+        let res = control.extract(name, &mut data_right[..], arg, true).unwrap();
+        assert_eq!(Some(()), res);
+        assert_eq!(data_left, data_right);
+    }
+
+
+    #[test]
+    fn control_extract_none() {
+        let control = Control::new(|_| Ok("imput via TTY...".to_string()) );
+        let mut data = [0u8; 16];
+        let right = control.extract("extract-arg", &mut data[..], None, false).unwrap();
+        assert_eq!(None, right);
+        assert_eq!([0u8; 16], data);
+    }
+
+
+    #[test]
+    fn control_extract_err() {
+        let name = "err-arg";
+        let mut data = [0u8; 17];
+        let msg_left = format!("{} shoud be at least {} bytes", name, data.len());
+
+        let arg = Some("imput a argument".to_string());
+        let control = Control::new(|_| Ok("imput via TTY...".to_string()) );
+        
+        let res = control.extract(name, &mut data[..], arg, false);
+        if let Err(CrateError::Arg(msg_right)) = res {
+            assert_eq!(msg_left, msg_right);
+        } else {
+            panic!("test should return an error message: {}", msg_left);
+        }
+    }
+
+
+    fn make_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crydec-test-{:016x}", OsRng.next_u64()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+}
@@ -0,0 +1,1015 @@
+use std::{
+    fs::File,
+    io::{Read, Write, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+use chacha20poly1305::{XChaCha20Poly1305, KeyInit, aead::{Aead, Payload}};
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher::{self, Cipher, TAG_SIZE},
+    error::Error,
+    hash::{HashParams, make_key},
+    header::{Header, KeySource, Slot, WrappedKey, DATA_KEY_SIZE},
+    io::{Input, Output},
+    recipient::{Recipient, Identity},
+    segment::{self, Counter, Segment, Tail, FRAME_SIZE, COUNTS_SIZE, segment_nonce},
+    stream::{EncryptWriter, DecryptReader, SeekableDecryptor},
+};
+
+
+/// Size of key file content
+pub const KEY_FILE_SIZE: usize = 32;
+/// Size of key data key is wrapped by
+const WRAP_KEY_SIZE: u32 = 32;
+
+
+/// Encryption params, salt and nonce are generated randomly if not set
+#[derive(Clone, Debug)]
+pub struct Params {
+    pub cipher: Cipher,
+    /// Argon2 params of password key slots
+    pub hash: HashParams,
+    /// Salt of password key slots, every slot gets its own random salt if not set
+    pub salt: Option<Zeroizing<[u8; 32]>>,
+    /// Has to be exactly `Header::nonce_size(cipher)` bytes
+    pub nonce: Option<Zeroizing<Vec<u8>>>,
+    /// Size of plaintext chunk, from `CHUNK_SIZE_MIN` to `CHUNK_SIZE_MAX` bytes
+    pub chunk_size: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            cipher: Cipher::XChacha20Poly1305,
+            hash: HashParams::default(),
+            salt: None,
+            nonce: None,
+            chunk_size: cipher::CHUNK_SIZE_DEFAULT,
+        }
+    }
+}
+
+
+/// Secret the wrap key of key slot is made from
+#[derive(Clone)]
+pub enum Secret {
+    Password(Zeroizing<String>),
+    /// Raw key of `KEY_FILE_SIZE` bytes
+    KeyFile(Zeroizing<Vec<u8>>),
+    /// Password with key file content mixed into argon2 as a second factor
+    PasswordKeyFile(Zeroizing<String>, Zeroizing<Vec<u8>>),
+    /// Public key to wrap data key for, able to encrypt only
+    Recipient(Recipient),
+    /// Secret key to unwrap data key, encrypts for its own public key
+    Identity(Identity),
+}
+
+impl Secret {
+    pub fn key_source(&self) -> KeySource {
+        match self {
+            Self::Password(_) => KeySource::Password,
+            Self::KeyFile(_) => KeySource::KeyFile,
+            Self::PasswordKeyFile(_, _) => KeySource::PasswordKeyFile,
+            Self::Recipient(_) | Self::Identity(_) => KeySource::Recipient,
+        }
+    }
+
+    /// Makes key slot with data key wrapped by key made from the secret
+    fn make_slot(&self, hash: &HashParams, salt: Zeroizing<[u8; 32]>, aad: &[u8], data_key: &[u8], cache: &KeyCache) -> Result<Slot, Error> {
+        let wrapped_key = WrappedKey::default();
+        let (mut slot, wrap_key) = match self {
+            Self::Password(password) => {
+                let wrap_key = cache.make_key(hash, password, salt.as_ref(), &[])?;
+                (Slot::Password { hash: hash.clone(), salt, wrapped_key }, wrap_key)
+            },
+            Self::KeyFile(key) => {
+                check_key_file(key)?;
+                (Slot::KeyFile { wrapped_key }, key.clone())
+            },
+            Self::PasswordKeyFile(password, key) => {
+                check_key_file(key)?;
+                let wrap_key = cache.make_key(hash, password, salt.as_ref(), key)?;
+                (Slot::PasswordKeyFile { hash: hash.clone(), salt, wrapped_key }, wrap_key)
+            },
+            Self::Recipient(recipient) => {
+                let (ephemeral, wrap_key) = recipient.make_wrap_key();
+                (Slot::Recipient { ephemeral, wrapped_key }, Zeroizing::new(wrap_key.to_vec()))
+            },
+            Self::Identity(identity) => {
+                let (ephemeral, wrap_key) = identity.to_recipient().make_wrap_key();
+                (Slot::Recipient { ephemeral, wrapped_key }, Zeroizing::new(wrap_key.to_vec()))
+            },
+        };
+        let aad = [aad, &slot.params_bytes()].concat();
+        *slot.wrapped_key_mut() = wrap(&wrap_key, &aad, data_key)?;
+        Ok(slot)
+    }
+
+    /// Unwraps data key of key slot, key source of slot has to match the secret
+    fn open_slot(&self, slot: &Slot, aad: &[u8], cache: &KeyCache) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let wrap_key = match (self, slot) {
+            (Self::Password(password), Slot::Password { hash, salt, .. }) => {
+                cache.make_key(hash, password, salt.as_ref(), &[])?
+            },
+            (Self::KeyFile(key), Slot::KeyFile { .. }) => {
+                check_key_file(key)?;
+                key.clone()
+            },
+            (Self::PasswordKeyFile(password, key), Slot::PasswordKeyFile { hash, salt, .. }) => {
+                check_key_file(key)?;
+                cache.make_key(hash, password, salt.as_ref(), key)?
+            },
+            (Self::Identity(identity), Slot::Recipient { ephemeral, .. }) => {
+                Zeroizing::new(identity.make_wrap_key(ephemeral).to_vec())
+            },
+            (Self::Recipient(_), _) => {
+                return Err(Error::make_arg("recipient public key is unable to decrypt, identity is required"));
+            },
+            _ => return Err(Error::make_arg(format!("key source {:?} is required, got {:?}", slot.key_source(), self.key_source()))),
+        };
+        let aad = [aad, &slot.params_bytes()].concat();
+        unwrap(&wrap_key, &aad, slot.wrapped_key())
+    }
+
+    /// Unwraps data key of the first key slot matching the secret, returns index of the slot and data key
+    fn open_slots(&self, header: &Header, cache: &KeyCache) -> Result<(usize, Zeroizing<Vec<u8>>), Error> {
+        let aad = header.aad();
+        let mut res = Err(Error::make_arg(format!("no key slot for {:?}, key slots: {:?}", self.key_source(),
+            header.slots.iter().map(Slot::key_source).collect::<Vec<_>>())));
+        for (idx, slot) in header.slots.iter().enumerate().filter(|(_, slot)| slot.key_source() == self.key_source()) {
+            res = self.open_slot(slot, &aad, cache).map(|data_key| (idx, data_key));
+            if res.is_ok() {
+                break;
+            }
+        }
+        match res {
+            Err(Error::Aead(_)) => Err(Error::make_slot(format!(
+                "no {} key slot could be opened: wrong password or key, or key slot is tampered", self.key_source().get_name()))),
+            res => res,
+        }
+    }
+
+    /// Unwraps data key of the first key slot matching the secret, returns cipher key
+    fn open(&self, header: &Header, cache: &KeyCache) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let (_, data_key) = self.open_slots(header, cache)?;
+        let (_, key_size, _) = header.cipher.get_spec();
+        Ok(Zeroizing::new(data_key[..key_size as usize].to_vec()))
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(password: &str) -> Self {
+        Self::Password(Zeroizing::new(password.to_owned()))
+    }
+}
+
+/// Keys derived by argon2 with their inputs, so secret used for many files
+/// sharing salt, e.g. by batch encryption, costs a single derivation
+#[derive(Default)]
+struct KeyCache {
+    keys: Mutex<Vec<CachedKey>>,
+}
+
+struct CachedKey {
+    hash: HashParams,
+    password: Zeroizing<String>,
+    salt: Vec<u8>,
+    secret: Zeroizing<Vec<u8>>,
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl KeyCache {
+    /// Derives wrap key unless it is derived from the same inputs before
+    fn make_key(&self, hash: &HashParams, password: &str, salt: &[u8], secret: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let mut keys = self.keys.lock().expect("key cache should not be poisoned");
+        let cached = keys.iter().find(|cached| {
+            cached.hash == *hash && cached.password.as_str() == password && cached.salt == salt && cached.secret.as_slice() == secret
+        });
+        if let Some(cached) = cached {
+            return Ok(cached.key.clone());
+        }
+        let key = make_key(hash, password, salt, secret, WRAP_KEY_SIZE)?;
+        keys.push(CachedKey {
+            hash: hash.clone(),
+            password: Zeroizing::new(password.to_owned()),
+            salt: salt.to_vec(),
+            secret: Zeroizing::new(secret.to_vec()),
+            key: key.clone(),
+        });
+        Ok(key)
+    }
+}
+
+fn check_key_file(key: &[u8]) -> Result<(), Error> {
+    if key.len() != KEY_FILE_SIZE {
+        return Err(Error::make_arg(format!("key file should be {} bytes", KEY_FILE_SIZE)));
+    }
+    Ok(())
+}
+
+fn wrap(wrap_key: &[u8], aad: &[u8], data_key: &[u8]) -> Result<WrappedKey, Error> {
+    let mut wrapped_key = WrappedKey::default();
+    OsRng.fill_bytes(&mut wrapped_key.nonce);
+    let key = XChaCha20Poly1305::new(wrap_key.into())
+        .encrypt(&wrapped_key.nonce.into(), Payload { msg: data_key, aad })
+        .map_err(Error::make_aead)?;
+    wrapped_key.key.copy_from_slice(&key);
+    Ok(wrapped_key)
+}
+
+fn unwrap(wrap_key: &[u8], aad: &[u8], wrapped_key: &WrappedKey) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let key = XChaCha20Poly1305::new(wrap_key.into())
+        .decrypt(&wrapped_key.nonce.into(), Payload { msg: &wrapped_key.key, aad })
+        .map_err(Error::make_aead)?;
+    Ok(Zeroizing::new(key))
+}
+
+
+/// Replaces key slot opened by old secret with key slot of new secret, encrypted data is not touched.
+/// Password slot gets fresh salt, `hash` maps argon2 params of the old slot to params of the new one.
+/// Secrets have to be of the same key source, so serialized spec keeps its size and may be rewritten in place
+pub fn rekey(header: &mut Header, old: &Secret, new: &Secret, hash: impl FnOnce(&HashParams) -> HashParams) -> Result<(), Error> {
+    if old.key_source() != new.key_source() {
+        return Err(Error::make_arg(format!("key source {:?} is unable to replace {:?}", new.key_source(), old.key_source())));
+    }
+    let cache = KeyCache::default();
+    let (idx, data_key) = old.open_slots(header, &cache)?;
+    let hash = hash( header.slots[idx].hash().unwrap_or(&HashParams::default()) );
+    header.slots[idx] = new.make_slot(&hash, make_salt(), &header.aad(), &data_key, &cache)?;
+    Ok(())
+}
+
+
+/// Encrypts stream of bytes with random data key wrapped for every secret.
+/// Password key is derived once for all streams if salt is set by params
+pub struct Encryptor {
+    params: Params,
+    secrets: Vec<Secret>,
+    threads: usize,
+    cache: Arc<KeyCache>,
+}
+
+impl Encryptor {
+    pub fn new(params: Params, secret: impl Into<Secret>) -> Self {
+        Self { params, secrets: vec![secret.into()], threads: 1, cache: Arc::default() }
+    }
+
+    /// Adds key slot for one more secret, any one of secrets is enough to decrypt
+    pub fn with_secret(mut self, secret: impl Into<Secret>) -> Self {
+        self.secrets.push(secret.into());
+        self
+    }
+
+    /// Sets number of threads to encrypt chunks in parallel, output does not depend on it
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Makes decryptor sharing keys derived by this encryptor, e.g. to verify its output without argon2 rerun
+    pub fn decryptor(&self, secret: impl Into<Secret>) -> Decryptor {
+        Decryptor { secret: secret.into(), threads: self.threads, cache: Arc::clone(&self.cache) }
+    }
+
+    /// Encrypts data from reader to writer, spec is written as ciphertext header
+    pub fn encrypt<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<(), Error> {
+        let mut input = Input::new( Box::new(reader) );
+        let mut output = Output::new( Box::new(writer) );
+        self.transfer(&mut input, &mut output, None)
+    }
+
+    /// Encrypts data from reader to writer, spec is written to a separate spec writer
+    pub fn encrypt_detached<R: Read, W: Write, S: Write>(&self, reader: R, writer: W, spec: S) -> Result<(), Error> {
+        let mut input = Input::new( Box::new(reader) );
+        let mut output = Output::new( Box::new(writer) );
+        let mut spec = Output::new( Box::new(spec) );
+        self.transfer(&mut input, &mut output, Some(&mut spec))
+    }
+
+    /// Writes spec as ciphertext header, returns writer encrypting data written into it
+    pub fn writer<W: Write>(&self, mut writer: W) -> Result<EncryptWriter<W>, Error> {
+        let (header, key) = self.prepare(false)?;
+        header.write(&mut Output::new( Box::new(&mut writer) ))?;
+        Ok(EncryptWriter::new(writer, header.cipher, &key[..], &header.nonce, &header.aad(), header.chunk_size as usize))
+    }
+
+    /// Appends data from reader as a new segment of segmented ciphertext file, file is created by the first append.
+    /// Spec of existing file is kept, one of secrets has to open its key slot. Segment left unfinished by interrupted
+    /// append is dropped, so file has to be appended by one writer at a time
+    pub fn append<R: Read>(&self, reader: R, file: &mut File) -> Result<(), Error> {
+        let len = file.seek(SeekFrom::End(0)).map_err(Error::make_io)?;
+        let (header, key, segments, tail, copy) = if len == 0 {
+            // Spec and count of no segments are written at once, so file is never left without count
+            let (header, key) = self.prepare(true)?;
+            let block = Counter::new(&key[..], &header.aad()).make_block()?;
+            file.write_all(&[header.to_bytes(), block].concat()).map_err(Error::make_io)?;
+            (header, key, Vec::new(), Tail::Unfinished(0), 0)
+        } else {
+            file.seek(SeekFrom::Start(0)).map_err(Error::make_io)?;
+            let header = Header::read(&mut Input::new( Box::new(&mut *file) ))?;
+            if !header.segmented {
+                return Err(Error::make_arg("unable to append to data encrypted without append"));
+            }
+            let key = self.open(&header)?;
+            let mut block = Vec::with_capacity(COUNTS_SIZE);
+            Read::by_ref(file).take(COUNTS_SIZE as u64).read_to_end(&mut block).map_err(Error::make_io)?;
+            let (count, copy) = Counter::new(&key[..], &header.aad()).open_for_append(&block)?;
+            let (segments, tail) = segment::scan(file, header.chunk_size as usize, count)?;
+            (header, key, segments, tail, copy)
+        };
+
+        // New segment replaces unfinished one
+        let header_len = header.to_bytes().len() as u64;
+        let start = header_len + COUNTS_SIZE as u64;
+        let offset = match tail {
+            Tail::End => segments.last().map_or(0, Segment::end),
+            Tail::Unfinished(offset) => {
+                file.set_len(start + offset).map_err(Error::make_io)?;
+                offset
+            },
+        };
+        let count = segments.len() as u64;
+        let index = u32::try_from(count).map_err(|_| Error::make_spec("too many segments to append one more"))?;
+
+        // Segment size is set only after its payload is written and synced, zero size marks unfinished segment
+        file.seek(SeekFrom::Start(start + offset)).map_err(Error::make_io)?;
+        file.write_all(&[0u8; FRAME_SIZE]).map_err(Error::make_io)?;
+        let stream = cipher::make_stream(header.cipher, &key[..], &segment_nonce(&header.nonce, index));
+        let mut input = Input::new( Box::new(reader) );
+        cipher::encrypt(&*stream, &header.aad(), header.chunk_size as usize, self.threads, &mut input, &mut Output::new( Box::new(&mut *file) ))?;
+        let size = file.stream_position().map_err(Error::make_io)? - (start + offset + FRAME_SIZE as u64);
+        file.sync_data().map_err(Error::make_io)?;
+        file.seek(SeekFrom::Start(start + offset)).map_err(Error::make_io)?;
+        file.write_all(&size.to_be_bytes()).map_err(Error::make_io)?;
+        file.sync_data().map_err(Error::make_io)?;
+
+        // Segment is committed by its count, older copy of count is overwritten
+        let sealed = Counter::new(&key[..], &header.aad()).seal(count + 1)?;
+        file.seek(SeekFrom::Start(header_len + copy)).map_err(Error::make_io)?;
+        file.write_all(&sealed).map_err(Error::make_io)?;
+        file.sync_data().map_err(Error::make_io)
+    }
+
+    fn transfer(&self, src: &mut Input, dst: &mut Output, spec: Option<&mut Output>) -> Result<(), Error> {
+        let (header, key) = self.prepare(false)?;
+        match spec {
+            Some(spec) => header.write(spec)?,
+            None => header.write(dst)?,
+        }
+        let stream = cipher::make_stream(header.cipher, &key[..], &header.nonce);
+        cipher::encrypt(&*stream, &header.aad(), header.chunk_size as usize, self.threads, src, dst)
+    }
+
+    /// Makes spec with generated nonce if not set and key slot for every secret, returns it with cipher key
+    fn prepare(&self, segmented: bool) -> Result<(Header, Zeroizing<Vec<u8>>), Error> {
+        let cipher = self.params.cipher;
+
+        let chunk_size = self.params.chunk_size;
+        if !(cipher::CHUNK_SIZE_MIN..=cipher::CHUNK_SIZE_MAX).contains(&chunk_size) {
+            return Err(Error::make_arg(format!("chunk size should be from {} to {} bytes", cipher::CHUNK_SIZE_MIN, cipher::CHUNK_SIZE_MAX)));
+        }
+
+        // Nonce preparing
+        let nonce_size = Header::nonce_size(cipher);
+        let nonce = if let Some(nonce) = &self.params.nonce {
+            if nonce.len() != nonce_size {
+                return Err(Error::make_arg(format!("nonce should be {} bytes for {:?}", nonce_size, cipher)));
+            }
+            nonce.clone()
+        } else {
+            let mut nonce = Zeroizing::new(vec![0u8; nonce_size]);
+            OsRng.fill_bytes(&mut nonce);
+            nonce
+        };
+
+        // Data key preparing
+        let mut data_key = Zeroizing::new(vec![0u8; DATA_KEY_SIZE]);
+        OsRng.fill_bytes(&mut data_key);
+
+        // Key slots preparing
+        let mut header = Header { cipher, nonce, chunk_size, slots: Vec::with_capacity(self.secrets.len()), segmented };
+        let aad = header.aad();
+        for secret in &self.secrets {
+            let salt = self.params.salt.clone().unwrap_or_else(make_salt);
+            header.slots.push( secret.make_slot(&self.params.hash, salt, &aad, &data_key, &self.cache)? );
+        }
+
+        let (_, key_size, _) = cipher.get_spec();
+        let key = Zeroizing::new(data_key[..key_size as usize].to_vec());
+        Ok((header, key))
+    }
+
+    /// Opens key slot of existing spec by the first secret able to do it
+    fn open(&self, header: &Header) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let mut res = Err(Error::make_arg("recipient public key is unable to open existing spec, password or key file is required"));
+        for secret in self.secrets.iter().filter(|secret| !matches!(secret, Secret::Recipient(_))) {
+            res = secret.open(header, &self.cache);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+}
+
+fn make_salt() -> Zeroizing<[u8; 32]> {
+    let mut salt = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(salt.as_mut());
+    salt
+}
+
+
+/// Decrypts stream of bytes with data key unwrapped from key slot of secret.
+/// Password key is derived once for all streams sharing argon2 params and salt
+pub struct Decryptor {
+    secret: Secret,
+    threads: usize,
+    cache: Arc<KeyCache>,
+}
+
+impl Decryptor {
+    pub fn new(secret: impl Into<Secret>) -> Self {
+        Self { secret: secret.into(), threads: 1, cache: Arc::default() }
+    }
+
+    /// Sets number of threads to decrypt chunks in parallel
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Decrypts data from reader to writer, spec is read from ciphertext header
+    pub fn decrypt<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<(), Error> {
+        let mut input = Input::new( Box::new(reader) );
+        let header = Header::read(&mut input)?;
+        self.transfer(&header, &mut input, &mut Output::new( Box::new(writer) ))
+    }
+
+    /// Decrypts data from reader to writer, spec is read from a separate spec reader
+    pub fn decrypt_detached<R: Read, W: Write, S: Read>(&self, reader: R, writer: W, spec: S) -> Result<(), Error> {
+        let header = Header::read(&mut Input::new( Box::new(spec) ))?;
+        self.decrypt_with_header(&header, reader, writer)
+    }
+
+    /// Decrypts data from reader to writer using spec read beforehand, e.g. to choose secret by key slots
+    pub fn decrypt_with_header<R: Read, W: Write>(&self, header: &Header, reader: R, writer: W) -> Result<(), Error> {
+        let mut input = Input::new( Box::new(reader) );
+        let mut output = Output::new( Box::new(writer) );
+        self.transfer(header, &mut input, &mut output)
+    }
+
+    /// Reads spec from ciphertext header, returns reader yielding decrypted data
+    pub fn reader<R: Read>(&self, mut reader: R) -> Result<DecryptReader<R>, Error> {
+        let header = Header::read(&mut Input::new( Box::new(&mut reader) ))?;
+        self.reader_with_header(&header, reader)
+    }
+
+    /// Returns reader yielding decrypted data using spec read beforehand
+    pub fn reader_with_header<R: Read>(&self, header: &Header, reader: R) -> Result<DecryptReader<R>, Error> {
+        if header.segmented {
+            return Err(Error::make_spec("data made of segments can be decrypted by decrypt or seekable reader only"));
+        }
+        let key = self.secret.open(header, &self.cache)?;
+        Ok(DecryptReader::new(reader, header.cipher, &key[..], &header.nonce, &header.aad(), header.chunk_size as usize))
+    }
+
+    /// Reads spec from ciphertext header, returns reader of plaintext at any offset decrypting only chunks it needs
+    pub fn seekable<R: Read + Seek>(&self, mut reader: R) -> Result<SeekableDecryptor<R>, Error> {
+        let header = Header::read(&mut Input::new( Box::new(&mut reader) ))?;
+        self.seekable_with_header(&header, reader)
+    }
+
+    /// Returns reader of plaintext at any offset using spec read beforehand, reader should be at the start of payload
+    pub fn seekable_with_header<R: Read + Seek>(&self, header: &Header, reader: R) -> Result<SeekableDecryptor<R>, Error> {
+        let key = self.secret.open(header, &self.cache)?;
+        if header.segmented {
+            SeekableDecryptor::new_segmented(reader, header.cipher, &key[..], &header.nonce, &header.aad(), header.chunk_size as usize)
+        } else {
+            SeekableDecryptor::new(reader, header.cipher, &key[..], &header.nonce, &header.aad(), header.chunk_size as usize)
+        }
+    }
+
+    fn transfer(&self, header: &Header, src: &mut Input, dst: &mut Output) -> Result<(), Error> {
+        let key = self.secret.open(header, &self.cache)?;
+        let chunk_size = header.chunk_size as usize;
+        if !header.segmented {
+            let stream = cipher::make_stream(header.cipher, &key[..], &header.nonce);
+            return cipher::decrypt(&*stream, &header.aad(), chunk_size, self.threads, src, dst);
+        }
+
+        // Count of finished segments is followed by segments, every one is a STREAM of its own
+        let mut block = Vec::with_capacity(COUNTS_SIZE);
+        src.take(COUNTS_SIZE as u64).into_inner().read_to_end(&mut block).map_err(Error::make_io)?;
+        let count = Counter::new(&key[..], &header.aad()).open(&block)?;
+        let mut prev: Option<Segment> = None;
+        for _ in 0..count {
+            let mut frame = [0u8; FRAME_SIZE];
+            let size = src.read(&mut frame)?;
+            let segment = Segment::parse(prev.as_ref(), &frame[..size], chunk_size + TAG_SIZE)?;
+            let stream = cipher::make_stream(header.cipher, &key[..], &segment_nonce(&header.nonce, segment.index));
+            cipher::decrypt(&*stream, &header.aad(), chunk_size, self.threads, &mut src.take(segment.size), dst)
+                .map_err(|err| segment.shift_err(err, chunk_size + TAG_SIZE))?;
+            prev = Some(segment);
+        }
+
+        // Data past counted segments is segment not finished yet, frame of unfinished segment is parsed to get its position
+        if count == 0 || src.read(&mut [0u8; 1])? != 0 {
+            Segment::parse(prev.as_ref(), &[], chunk_size + TAG_SIZE)?;
+        }
+        Ok(())
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{Read, Write, Seek, SeekFrom, Cursor},
+        path::PathBuf,
+    };
+    use rand::{RngCore, rngs::OsRng};
+    use zeroize::Zeroizing;
+    use crate::{
+        cipher::{self, Cipher},
+        error::{Error, ChunkPos},
+        hash::HashParams,
+        header::Header,
+        io::Input,
+        recipient::Identity,
+        segment::COUNTS_SIZE,
+    };
+    use super::{Params, Secret, Encryptor, Decryptor, rekey, KEY_FILE_SIZE};
+
+
+    #[test]
+    fn encrypt_decrypt_embedded() {
+        let data = make_data(1234);
+        let mut enc = Vec::new();
+        Encryptor::new(make_params(), "password").encrypt(&data[..], &mut enc).unwrap();
+
+        let mut dec = Vec::new();
+        Decryptor::new("password").decrypt(&enc[..], &mut dec).unwrap();
+        assert_eq!(data, dec);
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_detached() {
+        let data = make_data(1234);
+        let mut enc = Vec::new();
+        let mut spec = Vec::new();
+        Encryptor::new(make_params(), "password").encrypt_detached(&data[..], &mut enc, &mut spec).unwrap();
+
+        let mut dec = Vec::new();
+        Decryptor::new("password").decrypt_detached(&enc[..], &mut dec, &spec[..]).unwrap();
+        assert_eq!(data, dec);
+    }
+
+
+    #[test]
+    fn decrypt_wrong_password() {
+        let data = make_data(1234);
+        let mut enc = Vec::new();
+        Encryptor::new(make_params(), "password").encrypt(&data[..], &mut enc).unwrap();
+
+        let res = Decryptor::new("wrong password").decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Slot(_))));
+    }
+
+
+    #[test]
+    fn writer_reader() {
+        let data = make_data(1234);
+        let mut writer = Encryptor::new(make_params(), "password").writer(Vec::new()).unwrap();
+        writer.write_all(&data).unwrap();
+        let enc = writer.finish().unwrap();
+
+        let mut dec = Vec::new();
+        Decryptor::new("password").reader(&enc[..]).unwrap().read_to_end(&mut dec).unwrap();
+        assert_eq!(data, dec);
+
+        let mut dec = Vec::new();
+        Decryptor::new("password").decrypt(&enc[..], &mut dec).unwrap();
+        assert_eq!(data, dec);
+    }
+
+
+    #[test]
+    fn seekable_ranges() {
+        let data = make_data(cipher::CHUNK_SIZE_MIN as usize * 3 + 10);
+        let params = Params { chunk_size: cipher::CHUNK_SIZE_MIN, ..make_params() };
+        let mut enc = Vec::new();
+        Encryptor::new(params, "password").encrypt(&data[..], &mut enc).unwrap();
+
+        let mut reader = Decryptor::new("password").seekable(Cursor::new(&enc)).unwrap();
+        assert_eq!(data.len() as u64, reader.size());
+        let mut dec = vec![0u8; 1500];
+        reader.seek(SeekFrom::Start(1000)).unwrap();
+        reader.read_exact(&mut dec).unwrap();
+        assert_eq!(&data[1000..2500], &dec[..]);
+
+        let mut dec = Vec::new();
+        reader.seek(SeekFrom::End(-20)).unwrap();
+        reader.read_to_end(&mut dec).unwrap();
+        assert_eq!(&data[data.len() - 20..], &dec[..]);
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_chunk_sizes() {
+        for chunk_size in [cipher::CHUNK_SIZE_MIN, 4096, cipher::CHUNK_SIZE_DEFAULT] {
+            let data = make_data(chunk_size as usize * 2 + 3);
+            let mut enc = Vec::new();
+            let params = Params { chunk_size, ..make_params() };
+            Encryptor::new(params, "password").encrypt(&data[..], &mut enc).unwrap();
+
+            let mut dec = Vec::new();
+            Decryptor::new("password").decrypt(&enc[..], &mut dec).unwrap();
+            assert_eq!(data, dec, "chunk size: {}", chunk_size);
+        }
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_threads() {
+        let data = make_data(cipher::CHUNK_SIZE_MIN as usize * 9 + 5);
+        let params = Params { chunk_size: cipher::CHUNK_SIZE_MIN, ..make_params() };
+        let mut enc = Vec::new();
+        Encryptor::new(params, "password").with_threads(4).encrypt(&data[..], &mut enc).unwrap();
+
+        for threads in [1, 3] {
+            let mut dec = Vec::new();
+            Decryptor::new("password").with_threads(threads).decrypt(&enc[..], &mut dec).unwrap();
+            assert_eq!(data, dec, "threads: {}", threads);
+        }
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_key_cache() {
+        let params = Params { salt: Some(Zeroizing::new([3u8; 32])), ..make_params() };
+        let encryptor = Encryptor::new(params, "password").with_secret("other password");
+        let decryptor = Decryptor::new("other password");
+        for size in [0, 1234] {
+            let data = make_data(size);
+            let mut enc = Vec::new();
+            encryptor.encrypt(&data[..], &mut enc).unwrap();
+            let mut dec = Vec::new();
+            decryptor.decrypt(&enc[..], &mut dec).unwrap();
+            assert_eq!(data, dec);
+        }
+        // One derivation per password for every stream sharing salt
+        assert_eq!(2, encryptor.cache.keys.lock().unwrap().len());
+        assert_eq!(1, decryptor.cache.keys.lock().unwrap().len());
+    }
+
+
+    #[test]
+    fn encrypt_decryptor_shares_cache() {
+        let encryptor = Encryptor::new(make_params(), "password");
+        let data = make_data(1234);
+        let mut enc = Vec::new();
+        encryptor.encrypt(&data[..], &mut enc).unwrap();
+        let mut dec = Vec::new();
+        encryptor.decryptor("password").decrypt(&enc[..], &mut dec).unwrap();
+        assert_eq!(data, dec);
+        // Key of encryption is reused by decryption
+        assert_eq!(1, encryptor.cache.keys.lock().unwrap().len());
+
+        let res = encryptor.decryptor("wrong password").decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Slot(_))));
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_key_file() {
+        let data = make_data(1234);
+        for cipher in [Cipher::XChacha20Poly1305, Cipher::Aes128Gcm] {
+            let key = Zeroizing::new(make_data(KEY_FILE_SIZE));
+            let params = Params { cipher, ..make_params() };
+            let mut enc = Vec::new();
+            Encryptor::new(params, Secret::KeyFile(key.clone())).encrypt(&data[..], &mut enc).unwrap();
+
+            let mut dec = Vec::new();
+            Decryptor::new(Secret::KeyFile(key)).decrypt(&enc[..], &mut dec).unwrap();
+            assert_eq!(data, dec);
+
+            let res = Decryptor::new(Secret::KeyFile(Zeroizing::new(make_data(KEY_FILE_SIZE)))).decrypt(&enc[..], std::io::sink());
+            assert!(matches!(res, Err(Error::Slot(_))));
+            let res = Decryptor::new("password").decrypt(&enc[..], std::io::sink());
+            assert!(matches!(res, Err(Error::Arg(_))));
+        }
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_password_key_file() {
+        let data = make_data(1234);
+        let key = Zeroizing::new(make_data(KEY_FILE_SIZE));
+        let secret = |password: &str, key: &Zeroizing<Vec<u8>>| Secret::PasswordKeyFile(Zeroizing::new(password.to_owned()), key.clone());
+        let mut enc = Vec::new();
+        Encryptor::new(make_params(), secret("password", &key)).encrypt(&data[..], &mut enc).unwrap();
+
+        let mut dec = Vec::new();
+        Decryptor::new(secret("password", &key)).decrypt(&enc[..], &mut dec).unwrap();
+        assert_eq!(data, dec);
+
+        // Both factors are required
+        let res = Decryptor::new(secret("wrong password", &key)).decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Slot(_))));
+        let other_key = Zeroizing::new(make_data(KEY_FILE_SIZE));
+        let res = Decryptor::new(secret("password", &other_key)).decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Slot(_))));
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_recipient() {
+        let data = make_data(1234);
+        let identity = Identity::generate();
+        for cipher in [Cipher::XChacha20Poly1305, Cipher::Aes128Gcm] {
+            let params = Params { cipher, ..make_params() };
+            let mut enc = Vec::new();
+            Encryptor::new(params, Secret::Recipient(identity.to_recipient())).encrypt(&data[..], &mut enc).unwrap();
+
+            let mut dec = Vec::new();
+            Decryptor::new(Secret::Identity(identity.clone())).decrypt(&enc[..], &mut dec).unwrap();
+            assert_eq!(data, dec);
+
+            let res = Decryptor::new(Secret::Identity(Identity::generate())).decrypt(&enc[..], std::io::sink());
+            assert!(matches!(res, Err(Error::Slot(_))));
+            let res = Decryptor::new(Secret::Recipient(identity.to_recipient())).decrypt(&enc[..], std::io::sink());
+            assert!(matches!(res, Err(Error::Arg(_))));
+        }
+    }
+
+
+    #[test]
+    fn encrypt_decrypt_slots() {
+        let data = make_data(1234);
+        let key = Zeroizing::new(make_data(KEY_FILE_SIZE));
+        let identity = Identity::generate();
+        let mut enc = Vec::new();
+        Encryptor::new(make_params(), "first password")
+            .with_secret(Secret::KeyFile(key.clone()))
+            .with_secret(Secret::Recipient(identity.to_recipient()))
+            .with_secret("second password")
+            .encrypt(&data[..], &mut enc).unwrap();
+
+        let secrets = [
+            Secret::from("first password"),
+            Secret::KeyFile(key),
+            Secret::Identity(identity),
+            Secret::from("second password"),
+        ];
+        for secret in secrets {
+            let mut dec = Vec::new();
+            Decryptor::new(secret).decrypt(&enc[..], &mut dec).unwrap();
+            assert_eq!(data, dec);
+        }
+
+        let res = Decryptor::new("third password").decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Slot(_))));
+        let res = Decryptor::new(Secret::PasswordKeyFile(Zeroizing::new("first password".to_owned()), Zeroizing::new(make_data(KEY_FILE_SIZE))))
+            .decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Arg(_))));
+    }
+
+
+    #[test]
+    fn decrypt_tampered_slot() {
+        let data = make_data(1234);
+        let key = Zeroizing::new(make_data(KEY_FILE_SIZE));
+        let mut enc = Vec::new();
+        let mut spec = Vec::new();
+        Encryptor::new(make_params(), Secret::KeyFile(key.clone())).encrypt_detached(&data[..], &mut enc, &mut spec).unwrap();
+
+        // Every byte of key slot is authenticated
+        let slot_start = Header::read(&mut Input::new( Box::new(&spec[..]) )).unwrap().aad().len() + 1;
+        for idx in slot_start..spec.len() {
+            let mut spec = spec.clone();
+            spec[idx] ^= 0x01;
+            let res = Decryptor::new(Secret::KeyFile(key.clone())).decrypt_detached(&enc[..], std::io::sink(), &spec[..]);
+            assert!(res.is_err(), "byte: {}", idx);
+        }
+    }
+
+
+    #[test]
+    fn rekey_password() {
+        let data = make_data(1234);
+        let mut enc = Vec::new();
+        let mut spec = Vec::new();
+        Encryptor::new(make_params(), "old password").with_secret("other password")
+            .encrypt_detached(&data[..], &mut enc, &mut spec).unwrap();
+
+        let mut header = Header::read(&mut Input::new( Box::new(&spec[..]) )).unwrap();
+        let hash = |old: &HashParams| HashParams { time: old.time + 1, ..old.clone() };
+        rekey(&mut header, &Secret::from("old password"), &Secret::from("new password"), hash).unwrap();
+        assert_eq!(Some(2), header.slots[0].hash().map(|hash| hash.time));
+        let new_spec = header.to_bytes();
+        assert_eq!(spec.len(), new_spec.len());
+
+        for password in ["new password", "other password"] {
+            let mut dec = Vec::new();
+            Decryptor::new(password).decrypt_detached(&enc[..], &mut dec, &new_spec[..]).unwrap();
+            assert_eq!(data, dec);
+        }
+        let res = Decryptor::new("old password").decrypt_detached(&enc[..], std::io::sink(), &new_spec[..]);
+        assert!(matches!(res, Err(Error::Slot(_))));
+    }
+
+
+    #[test]
+    fn rekey_wrong_secret() {
+        let mut spec = Vec::new();
+        Encryptor::new(make_params(), "password").encrypt_detached(&b"data"[..], std::io::sink(), &mut spec).unwrap();
+        let mut header = Header::read(&mut Input::new( Box::new(&spec[..]) )).unwrap();
+
+        let key_file = Secret::KeyFile(Zeroizing::new(make_data(KEY_FILE_SIZE)));
+        let res = rekey(&mut header, &Secret::from("password"), &key_file, HashParams::clone);
+        assert!(matches!(res, Err(Error::Arg(_))));
+        let res = rekey(&mut header, &Secret::from("wrong password"), &Secret::from("new password"), HashParams::clone);
+        assert!(matches!(res, Err(Error::Slot(_))));
+        assert_eq!(spec, header.to_bytes());
+    }
+
+
+    #[test]
+    fn encrypt_wrong_key_file_size() {
+        let secret = Secret::KeyFile(Zeroizing::new(make_data(KEY_FILE_SIZE - 1)));
+        let res = Encryptor::new(make_params(), secret).encrypt(&b"data"[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Arg(_))));
+    }
+
+
+    #[test]
+    fn encrypt_wrong_chunk_size() {
+        let params = Params { chunk_size: cipher::CHUNK_SIZE_MAX + 1, ..make_params() };
+        let res = Encryptor::new(params, "password").encrypt(&b"data"[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Arg(_))));
+    }
+
+
+    #[test]
+    fn encrypt_wrong_nonce_size() {
+        let params = Params { nonce: Some(Zeroizing::new(vec![0u8; 3])), ..make_params() };
+        let res = Encryptor::new(params, "password").encrypt(&b"data"[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Arg(_))));
+    }
+
+
+    #[test]
+    fn append_segments() {
+        let path = make_path();
+        let params = Params { chunk_size: cipher::CHUNK_SIZE_MIN, ..make_params() };
+        let encryptor = Encryptor::new(params, "password");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        let mut expected = Vec::new();
+        for size in [1234, 0, cipher::CHUNK_SIZE_MIN as usize * 2] {
+            let data = make_data(size);
+            encryptor.append(&data[..], &mut file).unwrap();
+            expected.extend_from_slice(&data);
+        }
+
+        let enc = std::fs::read(&path).unwrap();
+        let mut dec = Vec::new();
+        Decryptor::new("password").with_threads(2).decrypt(&enc[..], &mut dec).unwrap();
+        assert_eq!(expected, dec);
+
+        // Random access across segment borders
+        let mut reader = Decryptor::new("password").seekable(Cursor::new(&enc)).unwrap();
+        assert_eq!(expected.len() as u64, reader.size());
+        let mut dec = vec![0u8; 100];
+        reader.seek(SeekFrom::Start(1200)).unwrap();
+        reader.read_exact(&mut dec).unwrap();
+        assert_eq!(&expected[1200..1300], &dec[..]);
+        assert!(matches!(Decryptor::new("password").reader(&enc[..]), Err(Error::Spec(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn append_unfinished() {
+        let path = make_path();
+        let encryptor = Encryptor::new(make_params(), "password");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        encryptor.append(&b"first"[..], &mut file).unwrap();
+        let size = file.metadata().unwrap().len();
+
+        // Interrupted append leaves zero size frame
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&[0u8; 8]).unwrap();
+        file.write_all(&make_data(100)).unwrap();
+        let enc = std::fs::read(&path).unwrap();
+        let header_len = Header::read(&mut Input::new( Box::new(&enc[..]) )).unwrap().to_bytes().len() as u64;
+        let res = Decryptor::new("password").decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 1, offset, .. })) if offset == size - header_len - COUNTS_SIZE as u64 + 8));
+        assert!(matches!(Decryptor::new("password").seekable(Cursor::new(&enc)), Err(Error::Truncated(_))));
+
+        encryptor.append(&b" second"[..], &mut file).unwrap();
+        let mut dec = Vec::new();
+        Decryptor::new("password").decrypt(File::open(&path).unwrap(), &mut dec).unwrap();
+        assert_eq!(b"first second", &dec[..]);
+
+        // Damaged segment is reported at its position in payload, its last chunk can not be told from cut one
+        let mut enc = std::fs::read(&path).unwrap();
+        let len = enc.len();
+        enc[len - 1] ^= 1;
+        let res = Decryptor::new("password").decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 1, last: true, .. }))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn append_cut_at_segment() {
+        let path = make_path();
+        let encryptor = Encryptor::new(make_params(), "password");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        encryptor.append(&b"first"[..], &mut file).unwrap();
+        let size = file.metadata().unwrap().len();
+        encryptor.append(&b" second"[..], &mut file).unwrap();
+
+        // Counted segment is missing after file is cut at segment border
+        file.set_len(size).unwrap();
+        let enc = std::fs::read(&path).unwrap();
+        let res = Decryptor::new("password").decrypt(&enc[..], std::io::sink());
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 1, .. }))));
+        assert!(matches!(Decryptor::new("password").seekable(Cursor::new(&enc)), Err(Error::Truncated(_))));
+        let res = encryptor.append(&b" third"[..], &mut file);
+        assert!(matches!(res, Err(Error::Truncated(_))));
+
+        // Spec without count of segments
+        let header_len = Header::read(&mut Input::new( Box::new(&enc[..]) )).unwrap().to_bytes().len();
+        let res = Decryptor::new("password").decrypt(&enc[..header_len], std::io::sink());
+        assert!(matches!(res, Err(Error::Truncated(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn append_cut_damaged_count() {
+        let path = make_path();
+        let encryptor = Encryptor::new(make_params(), "password");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        encryptor.append(&b"first"[..], &mut file).unwrap();
+        let size = file.metadata().unwrap().len();
+        encryptor.append(&b" second"[..], &mut file).unwrap();
+
+        // The newest copy of count is the first one after two appends, damaging it does not reveal the older count
+        let mut enc = std::fs::read(&path).unwrap();
+        let header_len = Header::read(&mut Input::new( Box::new(&enc[..]) )).unwrap().to_bytes().len();
+        enc[header_len] ^= 1;
+        for enc in [&enc[..], &enc[..size as usize]] {
+            let res = Decryptor::new("password").decrypt(enc, std::io::sink());
+            assert!(matches!(res, Err(Error::Spec(_))));
+            assert!(matches!(Decryptor::new("password").seekable(Cursor::new(enc)), Err(Error::Spec(_))));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn append_wrong_target() {
+        let path = make_path();
+        let mut enc = Vec::new();
+        Encryptor::new(make_params(), "password").encrypt(&b"data"[..], &mut enc).unwrap();
+        std::fs::write(&path, &enc).unwrap();
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let res = Encryptor::new(make_params(), "password").append(&b"data"[..], &mut file);
+        assert!(matches!(res, Err(Error::Arg(_))));
+
+        // Recipient is unable to open key slot of existing file
+        file.set_len(0).unwrap();
+        Encryptor::new(make_params(), "password").append(&b"data"[..], &mut file).unwrap();
+        let recipient = Secret::Recipient(Identity::generate().to_recipient());
+        let res = Encryptor::new(make_params(), recipient).append(&b"data"[..], &mut file);
+        assert!(matches!(res, Err(Error::Arg(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    fn make_path() -> PathBuf {
+        std::env::temp_dir().join(format!("crydec-test-{:016x}", OsRng.next_u64()))
+    }
+
+
+    fn make_params() -> Params {
+        let hash = HashParams { lanes: 1, memory: 64, ..Default::default() };
+        Params { cipher: Cipher::Aes256Gcm, hash, ..Default::default() }
+    }
+
+
+    fn make_data(size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+}
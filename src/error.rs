@@ -5,6 +5,29 @@ pub enum Error {
     Arg(String),
     Hash(argon2::Error),
     Spec(String),
+    /// No key slot matching the secret could be opened
+    Slot(String),
+    /// Encrypted chunk at given position failed authentication
+    Corrupted(ChunkPos),
+    /// Stream ended before the last chunk, position of the first missing chunk is given
+    Truncated(ChunkPos),
+}
+
+/// Position of encrypted chunk in payload following the spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkPos {
+    pub index: u64,
+    /// Byte offset of chunk from the start of payload
+    pub offset: u64,
+    /// Chunk was read as the last one of stream
+    pub last: bool,
+}
+
+impl ChunkPos {
+    /// Position of chunk with given index, chunk_len includes tag
+    pub fn new(index: u64, chunk_len: usize, last: bool) -> Self {
+        Self { index, offset: index.saturating_mul(chunk_len as u64), last }
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -15,16 +38,68 @@ impl std::fmt::Display for Error {
             Error::Arg(err) => write!(f, "argument: {}", err),
             Error::Hash(err) => write!(f, "hash argon2: {}", err),
             Error::Spec(err) => write!(f, "spec: {}", err),
-        }   
+            Error::Slot(err) => write!(f, "key slot: {}", err),
+            Error::Corrupted(pos) if pos.index == 0 => write!(f,
+                "corrupted: first chunk failed authentication, wrong key or spec for this data, or data is damaged at its start"),
+            Error::Corrupted(pos) => write!(f,
+                "corrupted: chunk {} at payload offset {} failed authentication, data is damaged there", pos.index, pos.offset),
+            Error::Truncated(pos) if pos.last => write!(f,
+                "truncated: last chunk {} at payload offset {} failed authentication, data is cut inside it or damaged there", pos.index, pos.offset),
+            Error::Truncated(pos) => write!(f,
+                "truncated: payload ended at offset {} before the last chunk, chunk {} and later are missing", pos.offset, pos.index),
+        }
     }
 }
 
 impl Error {
+    /// Stable name of error kind
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::Aead(_) => "aead",
+            Error::Arg(_) => "arg",
+            Error::Hash(_) => "hash",
+            Error::Spec(_) => "spec",
+            Error::Slot(_) => "slot",
+            Error::Corrupted(_) => "corrupted",
+            Error::Truncated(_) => "truncated",
+        }
+    }
+
     pub fn make_io(err: std::io::Error) -> Self { Self::Io(err) }
     pub fn make_aead(err: aead::Error) -> Self { Self::Aead(err) }
     pub fn make_arg(err: impl ToString) -> Self { Self::Arg(err.to_string()) }
     pub fn make_hash(err: argon2::Error) -> Self { Self::Hash(err) }
     pub fn make_spec(err: impl ToString) -> Self { Self::Spec(err.to_string()) }
+    pub fn make_slot(err: impl ToString) -> Self { Self::Slot(err.to_string()) }
+    pub fn make_corrupted(pos: ChunkPos) -> Self { Self::Corrupted(pos) }
+    pub fn make_truncated(pos: ChunkPos) -> Self { Self::Truncated(pos) }
+
+    /// Error of chunk failed authentication: the last chunk is shorter than others,
+    /// so it is taken as cut inside, while any other chunk is damaged
+    pub fn make_chunk_failure(pos: ChunkPos) -> Self {
+        if pos.last { Self::Truncated(pos) } else { Self::Corrupted(pos) }
+    }
 }
 
 impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    /// Unwraps error of the crate passed through io error, e.g. by `DecryptReader`
+    fn from(err: std::io::Error) -> Self {
+        if err.get_ref().map_or(false, |inner| inner.is::<Error>()) {
+            *err.into_inner().and_then(|inner| inner.downcast().ok()).expect("inner error is checked")
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::Other, err),
+        }
+    }
+}
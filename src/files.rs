@@ -0,0 +1,404 @@
+use std::{
+    fs::{File, Metadata, OpenOptions, Permissions},
+    io::{Write, Stdout, stdin, stdout, Result as IoResult},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use filetime::FileTime;
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+
+use crydec::{error::Error, io::Input, Identity};
+
+
+/// Output path of in-place encryption, suffix is appended to file name
+pub fn add_suffix(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let name = path.file_name()
+        .ok_or_else(|| Error::make_arg(format!("path should name a file: {}", path.display())))?;
+    let mut name = name.to_os_string();
+    name.push(suffix);
+    Ok(path.with_file_name(name))
+}
+
+/// Output path of in-place decryption, suffix is removed from file name
+pub fn strip_suffix(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let name = path.file_name().and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(suffix))
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| Error::make_arg(format!("file name should end with {}: {}", suffix, path.display())))?;
+    Ok(path.with_file_name(name))
+}
+
+/// Lists every entry of directory tree except directories themselves, sorted by path.
+/// Symbolic links are listed as they are, without following them
+pub fn list_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).map_err(Error::make_io)? {
+            let entry = entry.map_err(Error::make_io)?;
+            if entry.file_type().map_err(Error::make_io)?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Overwrites file content with zeros before removing it. Copies kept by filesystem
+/// or storage device, e.g. journal or SSD remapping, are out of reach.
+pub fn remove_file_secure(path: &Path) -> Result<(), Error> {
+    let mut file = OpenOptions::new().write(true).open(path).map_err(Error::make_io)?;
+    let mut left = file.metadata().map_err(Error::make_io)?.len();
+    let zeros = [0u8; 64 * 1024];
+    while left > 0 {
+        let size = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..size]).map_err(Error::make_io)?;
+        left -= size as u64;
+    }
+    file.sync_all().map_err(Error::make_io)?;
+    drop(file);
+    std::fs::remove_file(path).map_err(Error::make_io)
+}
+
+/// Writes identity file readable by owner only, never overwrites existing file
+pub fn write_identity(path: PathBuf, identity: &Identity) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(Error::make_io)?;
+    file.write_all(identity.to_text().as_bytes()).map_err(Error::make_io)
+}
+
+pub fn make_inout(path_in: Option<PathBuf>, path_out: Option<PathBuf>, force: bool) -> Result<(Input<'static>, Destination), Error> {
+    if let (Some(path_in), Some(path_out)) = (&path_in, &path_out) {
+        check_distinct(path_in, path_out)?;
+    }
+    let input = make_input(path_in)?;
+    let output = make_output(path_out, force)?;
+    Ok((input, output))
+}
+
+pub fn make_input(path: Option<PathBuf>) -> Result<Input<'static>, Error> {
+    if let Some(path) = path {
+        Ok(Input::new( Box::new( File::open(path).map_err(Error::make_io)? ) ))
+    } else {
+        Ok(Input::new( Box::new(stdin()) ))
+    }
+}
+
+/// Output file is created next to its path and appears there only after commit,
+/// existing file is replaced only if forced
+pub fn make_output(path: Option<PathBuf>, force: bool) -> Result<Destination, Error> {
+    if let Some(path) = path {
+        if !force && path.symlink_metadata().is_ok() {
+            return Err(Error::make_arg(format!("file {} already exists, use --force to overwrite it", path.display())));
+        }
+        Ok(Destination::File( AtomicFile::create(path)? ))
+    } else {
+        Ok(Destination::Stdout(stdout()))
+    }
+}
+
+/// Fails if both paths lead to the same file, e.g. when input and output are swapped by mistake
+pub fn check_distinct(path_a: &Path, path_b: &Path) -> Result<(), Error> {
+    let is_same = match (std::fs::metadata(path_a), std::fs::metadata(path_b)) {
+        (Ok(meta_a), Ok(meta_b)) => is_same_file(path_a, &meta_a, path_b, &meta_b),
+        _ => path_a == path_b,
+    };
+    if is_same {
+        return Err(Error::make_arg(format!("{} and {} are the same file", path_a.display(), path_b.display())));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_same_file(_: &Path, meta_a: &Metadata, _: &Path, meta_b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta_a.dev() == meta_b.dev() && meta_a.ino() == meta_b.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(path_a: &Path, _: &Metadata, path_b: &Path, _: &Metadata) -> bool {
+    matches!((path_a.canonicalize(), path_b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+pub fn read_key_file(path: PathBuf) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let key = std::fs::read(path).map_err(Error::make_io)?;
+    Ok(Zeroizing::new(key))
+}
+
+pub fn read_identity(path: PathBuf) -> Result<Identity, Error> {
+    let text = Zeroizing::new( std::fs::read_to_string(path).map_err(Error::make_io)? );
+    Identity::parse(&text)
+}
+
+
+/// Destination of command output, file one has to be committed after successful write
+pub enum Destination {
+    Stdout(Stdout),
+    File(AtomicFile),
+}
+
+impl Destination {
+    /// Makes file get permissions of source at once and its modification time on commit
+    pub fn copy_metadata(&mut self, source: &Path) -> Result<(), Error> {
+        match self {
+            Self::Stdout(_) => Ok(()),
+            Self::File(file) => file.copy_metadata(source),
+        }
+    }
+
+    pub fn set_permissions(&mut self, permissions: Permissions) -> Result<(), Error> {
+        match self {
+            Self::Stdout(_) => Ok(()),
+            Self::File(file) => file.set_permissions(permissions),
+        }
+    }
+
+    pub fn set_mtime(&mut self, mtime: SystemTime) {
+        if let Self::File(file) = self {
+            file.set_mtime(mtime);
+        }
+    }
+
+    pub fn commit(self) -> Result<(), Error> {
+        match self {
+            Self::Stdout(mut stdout) => stdout.flush().map_err(Error::make_io),
+            Self::File(file) => file.commit(),
+        }
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::File(file) => file.flush(),
+        }
+    }
+}
+
+
+/// File written under temporary name in the same directory, renamed over its path on commit.
+/// Temporary file is removed if it is dropped without commit, so failed write leaves no partial file.
+pub struct AtomicFile {
+    file: Option<File>,
+    path: PathBuf,
+    path_tmp: PathBuf,
+    mtime: Option<FileTime>,
+}
+
+impl AtomicFile {
+    pub fn create(path: PathBuf) -> Result<Self, Error> {
+        let name = path.file_name()
+            .ok_or_else(|| Error::make_arg(format!("output path should name a file: {}", path.display())))?;
+        let mut path_tmp = path.clone();
+        path_tmp.set_file_name(format!(".{}.{:016x}.tmp", name.to_string_lossy(), OsRng.next_u64()));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path_tmp).map_err(Error::make_io)?;
+        Ok(Self { file: Some(file), path, path_tmp, mtime: None })
+    }
+
+    /// Sets permissions of source before any data is written, its modification time is set on commit
+    pub fn copy_metadata(&mut self, source: &Path) -> Result<(), Error> {
+        let meta = std::fs::metadata(source).map_err(Error::make_io)?;
+        self.set_permissions(meta.permissions())?;
+        self.mtime = Some(FileTime::from_last_modification_time(&meta));
+        Ok(())
+    }
+
+    /// Sets permissions at once, so data is never readable by others if permissions do not allow it
+    pub fn set_permissions(&mut self, permissions: Permissions) -> Result<(), Error> {
+        self.file().set_permissions(permissions).map_err(Error::make_io)
+    }
+
+    /// Sets modification time to be set on commit
+    pub fn set_mtime(&mut self, mtime: SystemTime) {
+        self.mtime = Some(FileTime::from_system_time(mtime));
+    }
+
+    /// Syncs data to disk and replaces file at path with it
+    pub fn commit(mut self) -> Result<(), Error> {
+        let file = self.file.take().expect("file is taken only by commit");
+        let res = self.mtime.map_or(Ok(()), |mtime| filetime::set_file_handle_times(&file, None, Some(mtime)))
+            .and_then(|_| file.sync_all())
+            .and_then(|_| std::fs::rename(&self.path_tmp, &self.path));
+        if res.is_err() {
+            let _ = std::fs::remove_file(&self.path_tmp);
+        }
+        res.map_err(Error::make_io)
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("file is taken only by commit")
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.file().write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.file().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.path_tmp);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::{Path, PathBuf}};
+    use filetime::FileTime;
+    use rand::{RngCore, rngs::OsRng};
+    use crydec::error::Error;
+    use super::{AtomicFile, add_suffix, strip_suffix, remove_file_secure, list_files, make_output, check_distinct};
+
+
+    #[test]
+    fn in_place_suffix() {
+        let path = Path::new("dir/secrets.env");
+        assert_eq!(Path::new("dir/secrets.env.crydec"), add_suffix(path, ".crydec").unwrap());
+        assert_eq!(path, strip_suffix(Path::new("dir/secrets.env.crydec"), ".crydec").unwrap());
+        assert!(matches!(strip_suffix(path, ".crydec"), Err(Error::Arg(_))));
+        assert!(matches!(strip_suffix(Path::new(".crydec"), ".crydec"), Err(Error::Arg(_))));
+    }
+
+
+    #[test]
+    fn remove_file_secure_removes() {
+        let dir = make_dir();
+        let path = dir.join("file");
+        std::fs::write(&path, vec![7u8; 100_000]).unwrap();
+        remove_file_secure(&path).unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn list_files_tree() {
+        let dir = make_dir();
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::create_dir(dir.join("empty")).unwrap();
+        for path in ["z", "a/x", "a/b/y"] {
+            std::fs::write(dir.join(path), b"data").unwrap();
+        }
+        let files = list_files(&dir).unwrap();
+        assert_eq!(vec![dir.join("a/b/y"), dir.join("a/x"), dir.join("z")], files);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn atomic_file_commit() {
+        let dir = make_dir();
+        let path = dir.join("out");
+        std::fs::write(&path, b"old").unwrap();
+
+        let mut file = AtomicFile::create(path.clone()).unwrap();
+        file.write_all(b"new").unwrap();
+        // Target keeps old content until commit
+        assert_eq!(b"old", &std::fs::read(&path).unwrap()[..]);
+        file.commit().unwrap();
+        assert_eq!(b"new", &std::fs::read(&path).unwrap()[..]);
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn atomic_file_drop() {
+        let dir = make_dir();
+        let path = dir.join("out");
+
+        let mut file = AtomicFile::create(path.clone()).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        assert!(!path.exists());
+        assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn make_output_exists() {
+        let dir = make_dir();
+        let path = dir.join("out");
+        std::fs::write(&path, b"old").unwrap();
+
+        assert!(matches!(make_output(Some(path.clone()), false), Err(Error::Arg(_))));
+        let mut output = make_output(Some(path.clone()), true).unwrap();
+        output.write_all(b"new").unwrap();
+        output.commit().unwrap();
+        assert_eq!(b"new", &std::fs::read(&path).unwrap()[..]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn check_distinct_paths() {
+        let dir = make_dir();
+        let path = dir.join("file");
+        std::fs::write(&path, b"data").unwrap();
+        let other = dir.join("other");
+
+        assert!(check_distinct(&path, &other).is_ok());
+        assert!(matches!(check_distinct(&path, &path), Err(Error::Arg(_))));
+        // The same file by other path
+        assert!(matches!(check_distinct(&path, &dir.join(".").join("file")), Err(Error::Arg(_))));
+        assert!(matches!(check_distinct(&other, &other), Err(Error::Arg(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_file_copy_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = make_dir();
+        let source = dir.join("source");
+        std::fs::write(&source, b"data").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&source, mtime).unwrap();
+
+        let path = dir.join("out");
+        let mut file = AtomicFile::create(path.clone()).unwrap();
+        file.copy_metadata(&source).unwrap();
+        file.write_all(b"new").unwrap();
+        file.commit().unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(0o640, meta.permissions().mode() & 0o777);
+        assert_eq!(mtime, FileTime::from_last_modification_time(&meta));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+
+    fn make_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crydec-test-{:016x}", OsRng.next_u64()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+}
@@ -1,21 +1,46 @@
 use argon2::{Config, Variant, Version, ThreadMode};
+use clap::ValueEnum;
 use zeroize::Zeroizing;
 
-use crate::{
-    error::Error,
-    cli::{CfgHash},
-};
+use crate::error::Error;
 
 
-pub fn make_key(cfg: &CfgHash, password: &str, salt: &[u8], key_size: u32) -> Result<Zeroizing<Vec<u8>>, Error> {
+/// Argon2 params used to derive key from password
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HashParams {
+    pub hash_var: ArgonVariant,
+    pub hash_ver: ArgonVersion,
+    /// argon2 degree of parallelism
+    pub lanes: u32,
+    /// argon2 memory cost in kibibytes
+    pub memory: u32,
+    /// argon2 number of rounds to use
+    pub time: u32,
+}
+
+impl Default for HashParams {
+    fn default() -> Self {
+        Self {
+            hash_var: ArgonVariant::Argon2id,
+            hash_ver: ArgonVersion::Ver13,
+            lanes: 4,
+            memory: 2 * 1024 * 1024,
+            time: 1,
+        }
+    }
+}
+
+
+/// Derives key from password, optional secret value is mixed in by argon2 as a second factor
+pub fn make_key(cfg: &HashParams, password: &str, salt: &[u8], secret: &[u8], key_size: u32) -> Result<Zeroizing<Vec<u8>>, Error> {
     let version = match cfg.hash_ver {
-        crate::cli::ArgonVersion::Ver10 => Version::Version10,
-        crate::cli::ArgonVersion::Ver13 => Version::Version13,
+        ArgonVersion::Ver10 => Version::Version10,
+        ArgonVersion::Ver13 => Version::Version13,
     };
     let variant = match cfg.hash_var {
-        crate::cli::ArgonVariant::Argon2id => Variant::Argon2id,
-        crate::cli::ArgonVariant::Argon2i => Variant::Argon2i,
-        crate::cli::ArgonVariant::Argon2d => Variant::Argon2d,
+        ArgonVariant::Argon2id => Variant::Argon2id,
+        ArgonVariant::Argon2i => Variant::Argon2i,
+        ArgonVariant::Argon2d => Variant::Argon2d,
     };
     let config = Config {
         version,
@@ -24,9 +49,63 @@ pub fn make_key(cfg: &CfgHash, password: &str, salt: &[u8], key_size: u32) -> Re
         lanes: cfg.lanes,
         mem_cost: cfg.memory,
         time_cost: cfg.time,
+        secret,
         thread_mode: ThreadMode::Parallel,
         ..Default::default()
     };
     let hash = argon2::hash_raw(password.as_bytes(), salt, &config).map_err(Error::make_hash)?;
     Ok(Zeroizing::new(hash))
 }
+
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ArgonVariant {
+    Argon2i,
+    Argon2d,
+    Argon2id,
+}
+
+impl ArgonVariant {
+    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
+        let res = match type_id {
+            0 => Self::Argon2i,
+            1 => Self::Argon2d,
+            2 => Self::Argon2id,
+            _ => return Err(Error::make_spec("unable to define hash variant")),
+        };
+        Ok(res)
+    }
+
+    pub fn get_type_id(&self) -> u8 {
+        match self {
+            Self::Argon2i => 0,
+            Self::Argon2d => 1,
+            Self::Argon2id => 2,
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ArgonVersion {
+    Ver10,
+    Ver13,
+}
+
+impl ArgonVersion {
+    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
+        let res = match type_id {
+            0 => Self::Ver10,
+            1 => Self::Ver13,
+            _ => return Err(Error::make_spec("unable to define hash version")),
+        };
+        Ok(res)
+    }
+
+    pub fn get_type_id(&self) -> u8 {
+        match self {
+            Self::Ver10 => 0,
+            Self::Ver13 => 1,
+        }
+    }
+}
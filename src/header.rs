@@ -0,0 +1,472 @@
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher::{Cipher, CHUNK_SIZE_MIN, CHUNK_SIZE_MAX, TAG_SIZE},
+    error::Error,
+    hash::{HashParams, ArgonVariant, ArgonVersion},
+    io::{Input, Output},
+};
+
+
+/// Bytes every crydec spec starts with
+pub const MAGIC: [u8; 6] = *b"CRYDEC";
+/// Current spec format version, bumped on every incompatible layout change
+pub const VERSION: u8 = 1;
+/// Spec format version of payload made of segments appended one by one, see `segment` module
+pub const VERSION_SEGMENTED: u8 = 2;
+/// Size of random data key every chunk is encrypted by, ciphers with shorter keys use its prefix
+pub const DATA_KEY_SIZE: usize = 32;
+/// Size of wrapped data key: encrypted data key and its tag
+pub const WRAPPED_KEY_SIZE: usize = DATA_KEY_SIZE + TAG_SIZE;
+/// Size of random nonce data key is wrapped with
+pub const WRAP_NONCE_SIZE: usize = 24;
+
+
+/// Encryption spec: everything besides the secret needed to decrypt data
+pub struct Header {
+    pub cipher: Cipher,
+    pub nonce: Zeroizing<Vec<u8>>,
+    /// Size of plaintext chunk, every encrypted chunk is 16 bytes longer
+    pub chunk_size: u32,
+    /// Key slots, any one of them is enough to get data key
+    pub slots: Vec<Slot>,
+    /// Payload is made of count of finished segments and segments, every one is a STREAM of its own
+    pub segmented: bool,
+}
+
+impl Header {
+    /// Nonce size of cipher used by STREAM construction, AEAD requires 5 bytes of nonce to operate
+    pub fn nonce_size(cipher: Cipher) -> usize {
+        let (_, _, nonce_size) = cipher.get_spec();
+        nonce_size - 5
+    }
+
+    pub fn version(&self) -> u8 {
+        if self.segmented { VERSION_SEGMENTED } else { VERSION }
+    }
+
+    /// Serialized spec without key slots, used as associated data of every encrypted chunk and wrapped key.
+    /// Key slots are authenticated by their wrapped keys, so they can be changed without touching data
+    pub fn aad(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_data_spec(&mut Output::new( Box::new(&mut bytes) ))
+            .expect("writing to vec should never fail");
+        bytes
+    }
+
+    /// Serialized spec
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut Output::new( Box::new(&mut bytes) ))
+            .expect("writing to vec should never fail");
+        bytes
+    }
+
+    pub fn write(&self, spec: &mut Output) -> Result<(), Error> {
+        self.write_data_spec(spec)?;
+        if self.slots.is_empty() || self.slots.len() > u8::MAX as usize {
+            return Err(Error::make_arg(format!("number of key slots should be from 1 to {}", u8::MAX)));
+        }
+        spec.write_u8(self.slots.len() as u8)?;
+        for slot in &self.slots {
+            slot.write(spec)?;
+        }
+        Ok(())
+    }
+
+    fn write_data_spec(&self, spec: &mut Output) -> Result<(), Error> {
+        spec.write(&MAGIC)?;
+        spec.write_u8(self.version())?;
+        let (cipher_id, _, _) = self.cipher.get_spec();
+        spec.write_u8(cipher_id)?;
+        spec.write(&self.nonce)?;
+        spec.write_u32(self.chunk_size)?;
+        Ok(())
+    }
+
+    pub fn read(spec: &mut Input) -> Result<Self, Error> {
+        let mut magic = [0u8; MAGIC.len()];
+        let nread = spec.read(&mut magic)?;
+        if nread != MAGIC.len() || magic != MAGIC {
+            return Err(Error::make_spec("not a crydec file"));
+        }
+        let version = spec.read_u8()?;
+        if version != VERSION && version != VERSION_SEGMENTED {
+            return Err(Error::make_spec(format!("unsupported format version {}, expected {} or {}", version, VERSION, VERSION_SEGMENTED)));
+        }
+
+        let cipher = Cipher::from_type_id( spec.read_u8()? )?;
+        let mut nonce = Zeroizing::new(vec![0u8; Self::nonce_size(cipher)]);
+        spec.read_exact(&mut nonce)?;
+
+        let chunk_size = spec.read_u32()?;
+        if !(CHUNK_SIZE_MIN..=CHUNK_SIZE_MAX).contains(&chunk_size) {
+            return Err(Error::make_spec(format!("chunk size {} is out of range {}..={}", chunk_size, CHUNK_SIZE_MIN, CHUNK_SIZE_MAX)));
+        }
+
+        let slot_count = spec.read_u8()?;
+        if slot_count == 0 {
+            return Err(Error::make_spec("no key slots"));
+        }
+        let slots = (0..slot_count).map(|_| Slot::read(spec)).collect::<Result<_, _>>()?;
+
+        Ok(Self { cipher, nonce, chunk_size, slots, segmented: version == VERSION_SEGMENTED })
+    }
+}
+
+
+/// Key slot: data key wrapped by key made from one of secrets
+#[derive(Clone, Debug)]
+pub enum Slot {
+    /// Wrap key derived from password by argon2
+    Password { hash: HashParams, salt: Zeroizing<[u8; 32]>, wrapped_key: WrappedKey },
+    /// Wrap key read from key file
+    KeyFile { wrapped_key: WrappedKey },
+    /// Wrap key derived from password by argon2 using key file as secret value
+    PasswordKeyFile { hash: HashParams, salt: Zeroizing<[u8; 32]>, wrapped_key: WrappedKey },
+    /// Wrap key made by X25519 exchange of ephemeral key and recipient
+    Recipient { ephemeral: [u8; 32], wrapped_key: WrappedKey },
+}
+
+impl Slot {
+    pub fn key_source(&self) -> KeySource {
+        match self {
+            Self::Password { .. } => KeySource::Password,
+            Self::KeyFile { .. } => KeySource::KeyFile,
+            Self::PasswordKeyFile { .. } => KeySource::PasswordKeyFile,
+            Self::Recipient { .. } => KeySource::Recipient,
+        }
+    }
+
+    /// Argon2 params of password slots
+    pub fn hash(&self) -> Option<&HashParams> {
+        match self {
+            Self::Password { hash, .. } | Self::PasswordKeyFile { hash, .. } => Some(hash),
+            Self::KeyFile { .. } | Self::Recipient { .. } => None,
+        }
+    }
+
+    pub fn wrapped_key(&self) -> &WrappedKey {
+        match self {
+            Self::Password { wrapped_key, .. } => wrapped_key,
+            Self::KeyFile { wrapped_key } => wrapped_key,
+            Self::PasswordKeyFile { wrapped_key, .. } => wrapped_key,
+            Self::Recipient { wrapped_key, .. } => wrapped_key,
+        }
+    }
+
+    pub fn wrapped_key_mut(&mut self) -> &mut WrappedKey {
+        match self {
+            Self::Password { wrapped_key, .. } => wrapped_key,
+            Self::KeyFile { wrapped_key } => wrapped_key,
+            Self::PasswordKeyFile { wrapped_key, .. } => wrapped_key,
+            Self::Recipient { wrapped_key, .. } => wrapped_key,
+        }
+    }
+
+    /// Serialized slot without wrapped key, used with header as associated data of wrapped key
+    pub fn params_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_params(&mut Output::new( Box::new(&mut bytes) ))
+            .expect("writing to vec should never fail");
+        bytes
+    }
+
+    pub fn write(&self, spec: &mut Output) -> Result<(), Error> {
+        self.write_params(spec)?;
+        spec.write(&self.wrapped_key().nonce)?;
+        spec.write(&self.wrapped_key().key)?;
+        Ok(())
+    }
+
+    fn write_params(&self, spec: &mut Output) -> Result<(), Error> {
+        spec.write_u8(self.key_source().get_type_id())?;
+        match self {
+            Self::Password { hash, salt, .. } | Self::PasswordKeyFile { hash, salt, .. } => {
+                spec.write_u8(hash.hash_var.get_type_id())?;
+                spec.write_u8(hash.hash_ver.get_type_id())?;
+                spec.write_u32(hash.lanes)?;
+                spec.write_u32(hash.memory)?;
+                spec.write_u32(hash.time)?;
+                spec.write(salt.as_ref())?;
+            },
+            Self::KeyFile { .. } => {},
+            Self::Recipient { ephemeral, .. } => {
+                spec.write(ephemeral)?;
+            },
+        }
+        Ok(())
+    }
+
+    pub fn read(spec: &mut Input) -> Result<Self, Error> {
+        let slot = match KeySource::from_type_id( spec.read_u8()? )? {
+            KeySource::Password => {
+                let (hash, salt) = read_hash(spec)?;
+                Self::Password { hash, salt, wrapped_key: WrappedKey::read(spec)? }
+            },
+            KeySource::KeyFile => {
+                Self::KeyFile { wrapped_key: WrappedKey::read(spec)? }
+            },
+            KeySource::PasswordKeyFile => {
+                let (hash, salt) = read_hash(spec)?;
+                Self::PasswordKeyFile { hash, salt, wrapped_key: WrappedKey::read(spec)? }
+            },
+            KeySource::Recipient => {
+                let mut ephemeral = [0u8; 32];
+                spec.read_exact(&mut ephemeral)?;
+                Self::Recipient { ephemeral, wrapped_key: WrappedKey::read(spec)? }
+            },
+        };
+        Ok(slot)
+    }
+}
+
+fn read_hash(spec: &mut Input) -> Result<(HashParams, Zeroizing<[u8; 32]>), Error> {
+    let hash = HashParams {
+        hash_var: ArgonVariant::from_type_id( spec.read_u8()? )?,
+        hash_ver: ArgonVersion::from_type_id( spec.read_u8()? )?,
+        lanes: spec.read_u32()?,
+        memory: spec.read_u32()?,
+        time: spec.read_u32()?,
+    };
+    let mut salt = Zeroizing::new([0u8; 32]);
+    spec.read_exact(salt.as_mut())?;
+    Ok((hash, salt))
+}
+
+
+/// Data key encrypted by XChaCha20Poly1305 with wrap key of slot
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WrappedKey {
+    pub nonce: [u8; WRAP_NONCE_SIZE],
+    /// Encrypted data key and its tag
+    pub key: [u8; WRAPPED_KEY_SIZE],
+}
+
+impl Default for WrappedKey {
+    fn default() -> Self {
+        Self { nonce: [0u8; WRAP_NONCE_SIZE], key: [0u8; WRAPPED_KEY_SIZE] }
+    }
+}
+
+impl WrappedKey {
+    fn read(spec: &mut Input) -> Result<Self, Error> {
+        let mut wrapped_key = Self::default();
+        spec.read_exact(&mut wrapped_key.nonce)?;
+        spec.read_exact(&mut wrapped_key.key)?;
+        Ok(wrapped_key)
+    }
+}
+
+
+/// Secret the wrap key of slot is made from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeySource {
+    /// Key derived from password by argon2
+    Password,
+    /// Raw key read from key file
+    KeyFile,
+    /// Key derived from password by argon2 using key file as secret value
+    PasswordKeyFile,
+    /// Key made by X25519 exchange, identity is required to make it again
+    Recipient,
+}
+
+impl KeySource {
+    pub fn from_type_id(type_id: u8) -> Result<Self, Error> {
+        let res = match type_id {
+            0 => Self::Password,
+            1 => Self::KeyFile,
+            2 => Self::PasswordKeyFile,
+            3 => Self::Recipient,
+            _ => return Err(Error::make_spec("unable to define key source")),
+        };
+        Ok(res)
+    }
+
+    pub fn get_type_id(&self) -> u8 {
+        match self {
+            Self::Password => 0,
+            Self::KeyFile => 1,
+            Self::PasswordKeyFile => 2,
+            Self::Recipient => 3,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Password => "password",
+            Self::KeyFile => "key-file",
+            Self::PasswordKeyFile => "password-key-file",
+            Self::Recipient => "recipient",
+        }
+    }
+
+    pub fn needs_password(&self) -> bool {
+        matches!(self, Self::Password | Self::PasswordKeyFile)
+    }
+
+    pub fn needs_key_file(&self) -> bool {
+        matches!(self, Self::KeyFile | Self::PasswordKeyFile)
+    }
+
+    pub fn needs_identity(&self) -> bool {
+        matches!(self, Self::Recipient)
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{RngCore, rngs::OsRng};
+    use strum::IntoEnumIterator;
+    use zeroize::Zeroizing;
+    use crate::{
+        cipher::{Cipher, CHUNK_SIZE_MIN, CHUNK_SIZE_MAX},
+        error::Error,
+        hash::{HashParams, ArgonVariant, ArgonVersion},
+        io::{Input, Output},
+    };
+    use super::{Header, Slot, WrappedKey, MAGIC, VERSION, VERSION_SEGMENTED};
+
+
+    #[test]
+    fn header_write_read() {
+        for cipher in Cipher::iter() {
+            let header = make_header(cipher);
+            let bytes = write_header(&header);
+            assert_eq!(header.to_bytes(), bytes);
+            assert_eq!(&MAGIC, &bytes[..MAGIC.len()]);
+            assert_eq!(VERSION, bytes[MAGIC.len()]);
+            assert_eq!(header.aad(), bytes[..header.aad().len()]);
+
+            let mut input = Input::new(Box::new( std::io::Cursor::new(bytes) ));
+            let fact = Header::read(&mut input).unwrap();
+            assert_eq!(header.cipher, fact.cipher);
+            assert_eq!(header.nonce, fact.nonce);
+            assert_eq!(header.chunk_size, fact.chunk_size);
+            assert_eq!(header.slots.len(), fact.slots.len());
+            for (slot, fact_slot) in header.slots.iter().zip(&fact.slots) {
+                assert_eq!(slot.key_source(), fact_slot.key_source());
+                assert_eq!(slot.params_bytes(), fact_slot.params_bytes());
+                assert_eq!(slot.wrapped_key(), fact_slot.wrapped_key());
+            }
+            assert_eq!(header.to_bytes(), fact.to_bytes());
+        }
+    }
+
+
+    #[test]
+    fn header_segmented() {
+        let header = Header { segmented: true, ..make_header(Cipher::Aes256Gcm) };
+        let bytes = write_header(&header);
+        assert_eq!(VERSION_SEGMENTED, bytes[MAGIC.len()]);
+        let mut fact = Header::read(&mut Input::new(Box::new( &bytes[..] ))).unwrap();
+        assert!(fact.segmented);
+        // Version is a part of associated data, so layouts can not be swapped
+        let aad = fact.aad();
+        fact.segmented = false;
+        assert_ne!(aad, fact.aad());
+    }
+
+
+    #[test]
+    fn header_read_bad_magic() {
+        let mut bytes = write_header(&make_header(Cipher::XChacha20Poly1305));
+        bytes[0] ^= 0xff;
+        let mut input = Input::new(Box::new( std::io::Cursor::new(bytes) ));
+        match Header::read(&mut input) {
+            Err(Error::Spec(msg)) => assert_eq!("not a crydec file", msg),
+            _ => panic!("header with bad magic should be rejected"),
+        }
+    }
+
+
+    #[test]
+    fn header_read_short() {
+        let mut input = Input::new(Box::new( std::io::Cursor::new(b"CRY".to_vec()) ));
+        match Header::read(&mut input) {
+            Err(Error::Spec(msg)) => assert_eq!("not a crydec file", msg),
+            _ => panic!("short input should be rejected"),
+        }
+    }
+
+
+    #[test]
+    fn header_read_bad_version() {
+        let mut bytes = write_header(&make_header(Cipher::XChacha20Poly1305));
+        bytes[MAGIC.len()] = VERSION_SEGMENTED + 1;
+        let mut input = Input::new(Box::new( std::io::Cursor::new(bytes) ));
+        match Header::read(&mut input) {
+            Err(Error::Spec(msg)) => assert_eq!(format!("unsupported format version {}, expected {} or {}", VERSION_SEGMENTED + 1, VERSION, VERSION_SEGMENTED), msg),
+            _ => panic!("header with unknown version should be rejected"),
+        }
+    }
+
+
+    #[test]
+    fn header_read_bad_chunk_size() {
+        for chunk_size in [0, CHUNK_SIZE_MIN - 1, CHUNK_SIZE_MAX + 1] {
+            let header = Header { chunk_size, ..make_header(Cipher::XChacha20Poly1305) };
+            let mut input = Input::new(Box::new( std::io::Cursor::new(write_header(&header)) ));
+            assert!(matches!(Header::read(&mut input), Err(Error::Spec(_))), "chunk size: {}", chunk_size);
+        }
+    }
+
+
+    #[test]
+    fn header_no_slots() {
+        let header = Header { slots: Vec::new(), ..make_header(Cipher::XChacha20Poly1305) };
+        let res = header.write(&mut Output::new( Box::new(std::io::sink()) ));
+        assert!(matches!(res, Err(Error::Arg(_))));
+
+        let mut bytes = header.aad();
+        bytes.push(0);
+        let mut input = Input::new(Box::new( std::io::Cursor::new(bytes) ));
+        assert!(matches!(Header::read(&mut input), Err(Error::Spec(_))));
+    }
+
+
+    fn make_header(cipher: Cipher) -> Header {
+        let mut nonce = Zeroizing::new(vec![0u8; Header::nonce_size(cipher)]);
+        OsRng.fill_bytes(&mut nonce);
+        let hash = HashParams {
+            hash_var: ArgonVariant::Argon2d,
+            hash_ver: ArgonVersion::Ver10,
+            lanes: 3,
+            memory: 4096,
+            time: 2,
+        };
+        let slots = vec![
+            Slot::Password { hash: hash.clone(), salt: make_salt(), wrapped_key: make_wrapped_key() },
+            Slot::KeyFile { wrapped_key: make_wrapped_key() },
+            Slot::PasswordKeyFile { hash, salt: make_salt(), wrapped_key: make_wrapped_key() },
+            Slot::Recipient { ephemeral: *make_salt(), wrapped_key: make_wrapped_key() },
+        ];
+        Header { cipher, nonce, chunk_size: 4096, slots, segmented: false }
+    }
+
+
+    fn make_salt() -> Zeroizing<[u8; 32]> {
+        let mut salt = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(salt.as_mut());
+        salt
+    }
+
+
+    fn make_wrapped_key() -> WrappedKey {
+        let mut wrapped_key = WrappedKey::default();
+        OsRng.fill_bytes(&mut wrapped_key.nonce);
+        OsRng.fill_bytes(&mut wrapped_key.key);
+        wrapped_key
+    }
+
+
+    fn write_header(header: &Header) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        header.write(&mut Output::new( Box::new(&mut bytes) )).unwrap();
+        bytes
+    }
+}
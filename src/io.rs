@@ -1,85 +1,23 @@
-use std::{
-    fs::File,
-    io::{Read, Write, stdin, stdout, ErrorKind as IoErrorKind, Error as IoError, Result as IoResult},
-    path::PathBuf,
-};
-
-use zeroize::Zeroizing;
+use std::io::{Read, Write, ErrorKind as IoErrorKind, Error as IoError};
 
 use crate::error::Error;
 
 
-pub fn make_inout(path_in: Option<PathBuf>, path_out: Option<PathBuf>) -> Result<(Input, Output), Error> {
-    let input = if let Some(path) = path_in {
-        Input::new( Box::new( File::open(path).map_err(Error::make_io)? ) )
-    } else {
-        Input::new( Box::new(stdin()) )
-    };
-
-    let output = if let Some(path) = path_out {
-        Output::new( Box::new( File::create(path).map_err(Error::make_io)? ) )
-    } else {
-        Output::new( Box::new(stdout()) )
-    };
-
-    Ok((input, output))
-}
-
-pub fn make_control() -> Control {
-    Control::new(|req| rpassword::prompt_password(req))
-}
-
-
-pub struct Control {
-    func: fn(&str) -> IoResult<String>,
+pub struct Input<'a> {
+    reader: Box<dyn Read + 'a>,
 }
-
-impl Control {
-    fn new(func: fn(&str) -> IoResult<String>) -> Self {
-        Self { func }
-    }
-
-    pub fn prompt(&self, name: &str, arg: Option<String>) -> Result<Zeroizing<String>, Error> {
-        if let Some(val) = arg {
-            Ok(Zeroizing::new(val))
-        } else {
-            let request = format!("{}: ", name);
-            let val = (self.func)(&request).map_err(Error::make_io)?;
-            Ok(Zeroizing::new(val))
-        }
+impl <'a> Input<'a> {
+    pub fn new(reader: Box<dyn Read + 'a>) -> Self {
+        Self { reader }
     }
 
-    pub fn extract(&self, name: &str, slice: &mut [u8], arg: Option<String>, is_tty: bool) -> Result<Option<()>, Error> {
-        let text_opt = if let Some(arg) = arg {
-            Some(Zeroizing::new(arg))
-        } else if is_tty {
-            let request = format!("{}: ", name);
-            let password = (self.func)(&request).map_err(Error::make_io)?;
-            Some(Zeroizing::new(password))
-        } else {
-            None
-        };
-        if let Some(text) = text_opt {
-            let size = slice.len();
-            let bytes = text.as_bytes();
-            if bytes.len() < size {
-                return Err(Error::make_arg(format!("{} shoud be at least {} bytes", name, size)));
-            }
-            let _ = &slice.copy_from_slice(&bytes[..size]);
-            Ok(Some(()))
-        } else {
-            Ok(None)
-        }
+    pub fn into_inner(self) -> Box<dyn Read + 'a> {
+        self.reader
     }
-}
 
-
-pub struct Input {
-    reader: Box<dyn Read>,
-}
-impl Input {
-    pub fn new(reader: Box<dyn Read>) -> Self {
-        Self { reader }
+    /// Input reading up to limit bytes of this one
+    pub fn take(&mut self, limit: u64) -> Input<'_> {
+        Input::new( Box::new( (&mut self.reader).take(limit) ) )
     }
 
     pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
@@ -123,16 +61,20 @@ impl Input {
 }
 
 
-pub struct Output {
-    writer: Box<dyn Write>,
+pub struct Output<'a> {
+    writer: Box<dyn Write + 'a>,
 }
-impl Output {
-    pub fn new(writer: Box<dyn Write>) -> Self {
+impl <'a> Output<'a> {
+    pub fn new(writer: Box<dyn Write + 'a>) -> Self {
         Self { writer }
     }
 
+    pub fn into_inner(self) -> Box<dyn Write + 'a> {
+        self.writer
+    }
+
     pub fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
-        self.writer.write_all(buf).map_err(Error::make_io)
+        self.writer.write_all(buf).map_err(Error::from)
     }
 
     pub fn write_u8(&mut self, val: u8) -> Result<(), Error> {
@@ -154,9 +96,7 @@ mod tests {
         cell::RefCell,
         io::{Read, Write, Result, Error, ErrorKind},
     };
-    use zeroize::Zeroizing;
-    use crate::error::Error as CrateError;
-    use super::{Input, Output, Control};
+    use super::{Input, Output};
 
 
     #[test]
@@ -182,7 +122,7 @@ mod tests {
     #[test]
     fn input_read_u32() {
         const VALUE: u32 = 2217864614;
-        let bytes = VALUE.clone().to_be_bytes();
+        let bytes = VALUE.to_be_bytes();
         let mock_read = MockRead::new(bytes, 5);
         let mut input = Input::new( Box::new(mock_read) );
         assert_eq!(VALUE, input.read_u32().unwrap());
@@ -192,7 +132,7 @@ mod tests {
     #[test]
     fn input_read_u8() {
         const VALUE: u8 = 188;
-        let bytes = VALUE.clone().to_be_bytes();
+        let bytes = VALUE.to_be_bytes();
         let mock_read = MockRead::new(bytes, 5);
         let mut input = Input::new( Box::new(mock_read) );
         assert_eq!(VALUE, input.read_u8().unwrap());
@@ -260,108 +200,6 @@ mod tests {
     }
 
 
-    #[test]
-    fn control_prompt_arg() {
-        let name: &str = "prompt-arg";
-        const REQ_LEFT: &str = "prompt-arg: ";
-        let arg: Option<String> = Some("imput via argument".to_string());
-        let resp_left = Zeroizing::new("imput via argument".to_string());
-
-        let control = Control::new(|req| {
-            assert_eq!(REQ_LEFT, req);
-            Ok("imput via TTY".to_string())   
-        });
-        let resp_right = control.prompt(name, arg).unwrap();
-        assert_eq!(resp_left, resp_right);
-    }
-
-
-    #[test]
-    fn control_prompt_noarg() {
-        let name: &str = "prompt-noarg";
-        const REQ_LEFT: &str = "prompt-noarg: ";
-        let arg: Option<String> = None;
-        let resp_left = Zeroizing::new("imput via TTY".to_string());
-
-        let control = Control::new(|req| {
-            assert_eq!(REQ_LEFT, req);
-            Ok("imput via TTY".to_string())   
-        });
-        let resp_right = control.prompt(name, arg).unwrap();
-        assert_eq!(resp_left, resp_right);
-    }
-
-
-    #[test]
-    fn control_extract_arg() {
-        let name: &str = "extract-arg";
-        const REQ_LEFT: &str = "extract-arg: ";
-        let arg = Some("imput a argument".to_string());
-
-        let data = "imput a argument";
-        let data_left = data.as_bytes();
-        let mut data_right = [0u8; 16];
-
-        let control = Control::new(|req| {
-            assert_eq!(REQ_LEFT, req);
-            Ok("imput via TTY".to_string())   
-        });
-
-        let res = control.extract(name, &mut data_right[..], arg, true).unwrap();
-        assert_eq!(Some(()),res);
-        assert_eq!(data_left, data_right);
-    }
-
-
-    #[test]
-    fn control_extract_noarg() {
-        let name: &str = "extract-arg";
-        const REQ_LEFT: &str = "extract-arg: ";
-        let arg = None;
-
-        let data = "imput via TTY...";
-        let data_left = data.as_bytes();
-        let mut data_right = [0u8; 16];
-
-        let control = Control::new(|req| {
-            assert_eq!(REQ_LEFT, req);
-            Ok("imput via TTY...".to_string())   
-        });
-
-        let res = control.extract(name, &mut data_right[..], arg, true).unwrap();
-        assert_eq!(Some(()), res);
-        assert_eq!(data_left, data_right);
-    }
-
-
-    #[test]
-    fn control_extract_none() {
-        let control = Control::new(|_| Ok("imput via TTY...".to_string()) );
-        let mut data = [0u8; 16];
-        let right = control.extract("extract-arg", &mut data[..], None, false).unwrap();
-        assert_eq!(None, right);
-        assert_eq!([0u8; 16], data);
-    }
-
-
-    #[test]
-    fn control_extract_err() {
-        let name = "err-arg";
-        let mut data = [0u8; 17];
-        let msg_left = format!("{} shoud be at least {} bytes", name, data.len());
-
-        let arg = Some("imput a argument".to_string());
-        let control = Control::new(|_| Ok("imput via TTY...".to_string()) );
-        
-        let res = control.extract(name, &mut data[..], arg, false);
-        if let Err(CrateError::Arg(msg_right)) = res {
-            assert_eq!(msg_left, msg_right);
-        } else {
-            panic!("test should return an error message: {}", msg_left);
-        }
-    }
-
-
     fn input_read_base(size_step: usize, size_data: usize, size_sample: usize, size_buffer: usize) -> (Vec<u8>, Vec<u8>) {
         // Data set
         let mut vec_data = vec![0u8; size_data];
@@ -385,11 +223,11 @@ mod tests {
             let nread = input.read(&mut vec_buff).unwrap();
             if nread == size_buffer {
                 let idx_stop = idx_start + size_buffer;
-                (&mut vec_fact[idx_start..idx_stop]).copy_from_slice(&vec_buff);
+                vec_fact[idx_start..idx_stop].copy_from_slice(&vec_buff);
                 idx_start = idx_stop;
             } else {
                 let idx_stop = idx_start + nread;
-                (&mut vec_fact[idx_start..idx_stop]).copy_from_slice(&vec_buff[..nread]);
+                vec_fact[idx_start..idx_stop].copy_from_slice(&vec_buff[..nread]);
                 break;
             }
         }
@@ -0,0 +1,75 @@
+use std::fmt;
+
+
+/// Minimal JSON value, enough to print specs and errors
+pub enum Json {
+    Num(u64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Num(num) => write!(f, "{}", num),
+            Self::Str(text) => write_str(f, text),
+            Self::Arr(items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Self::Obj(fields) => {
+                write!(f, "{{")?;
+                for (idx, (name, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for ch in text.chars() {
+        match ch {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            ch if (ch as u32) < 0x20 => write!(f, "\\u{:04x}", ch as u32)?,
+            ch => write!(f, "{}", ch)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+
+    #[test]
+    fn json_display() {
+        let json = Json::Obj(vec![
+            ("num", Json::Num(42)),
+            ("str", Json::Str("a \"b\"\\\n\u{1}".to_owned())),
+            ("arr", Json::Arr(vec![Json::Num(1), Json::Obj(Vec::new())])),
+        ]);
+        assert_eq!(r#"{"num":42,"str":"a \"b\"\\\n\u0001","arr":[1,{}]}"#, json.to_string());
+    }
+}
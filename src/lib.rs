@@ -0,0 +1,33 @@
+//! Encrypts and decrypts stream of bytes using ChaCha and AES-GCM families of
+//! algorithms with key derived from password by argon2.
+//!
+//! ```no_run
+//! use crydec::{Encryptor, Decryptor, Params};
+//!
+//! let mut enc = Vec::new();
+//! Encryptor::new(Params::default(), "password").encrypt(&b"secret"[..], &mut enc)?;
+//!
+//! let mut dec = Vec::new();
+//! Decryptor::new("password").decrypt(&enc[..], &mut dec)?;
+//! # Ok::<(), crydec::Error>(())
+//! ```
+
+pub mod archive;
+pub mod cipher;
+pub mod error;
+pub mod hash;
+pub mod header;
+pub mod io;
+pub mod recipient;
+pub mod segment;
+pub mod stream;
+mod crypt;
+
+pub use crate::{
+    cipher::Cipher,
+    crypt::{Params, Secret, Encryptor, Decryptor, rekey, KEY_FILE_SIZE},
+    error::Error,
+    hash::HashParams,
+    recipient::{Recipient, Identity},
+    stream::{EncryptWriter, DecryptReader, SeekableDecryptor},
+};
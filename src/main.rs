@@ -1,27 +1,72 @@
-mod cipher;
-mod io;
-mod error;
 mod cli;
 mod command;
-mod hash;
+mod control;
+mod files;
+mod json;
+mod pack;
+mod tree;
 
 use std::io::Write;
 
 use clap::Parser;
 
-use cli::{Cli, Commands};
+use crydec::Error;
+
+use cli::{Cli, Commands, ErrorFormat};
+use json::Json;
 
 
 fn main() {
     let cli = Cli::parse();
     let res = match cli.command {
-        Commands::Encrypt(cfg) => command::encrypt(cfg.cipher, cfg.io, cfg.pwd_cli, cfg.hash, cfg.rand),
-        Commands::Decrypt(cfg) => command::decrypt(cfg.io, cfg.pwd_cli),
+        Commands::Encrypt(cfg) => command::encrypt(cfg),
+        Commands::Decrypt(cfg) => command::decrypt(cfg),
+        Commands::Verify(cfg) => command::verify(cfg),
+        Commands::Cat(cfg) => command::cat(cfg),
+        Commands::Rekey(cfg) => command::rekey(cfg),
+        Commands::Inspect(cfg) => command::inspect(cfg),
+        Commands::Keygen(cfg) => command::keygen(cfg),
+        Commands::Pack(cfg) => pack::pack(cfg),
+        Commands::Unpack(cfg) => pack::unpack(cfg),
+        Commands::List(cfg) => pack::list(cfg),
     };
     if let Err(err) = res {
-        let _ = writeln!(&mut std::io::stderr(), "ERROR: {}", err);
-        std::process::exit(1);
+        let code = exit_code(&err);
+        let _ = match cli.error_format {
+            ErrorFormat::Text => writeln!(&mut std::io::stderr(), "ERROR: {}", err),
+            ErrorFormat::Json => writeln!(&mut std::io::stderr(), "{}", error_json(&err, code)),
+        };
+        std::process::exit(code);
     } else {
         std::process::exit(0);
     }
 }
+
+
+/// Stable exit code of every error kind, 2 is also used by clap for bad usage
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Arg(_) => 2,
+        Error::Io(_) => 3,
+        Error::Spec(_) => 4,
+        Error::Hash(_) => 5,
+        Error::Aead(_) => 10,
+        Error::Corrupted(_) => 11,
+        Error::Truncated(_) => 12,
+        Error::Slot(_) => 13,
+    }
+}
+
+
+fn error_json(err: &Error, code: i32) -> Json {
+    let mut fields = vec![
+        ("error", Json::Str( err.get_name().to_owned() )),
+        ("code", Json::Num( code as u64 )),
+        ("message", Json::Str( err.to_string() )),
+    ];
+    if let Error::Corrupted(pos) | Error::Truncated(pos) = err {
+        fields.push(("chunk", Json::Num(pos.index)));
+        fields.push(("offset", Json::Num(pos.offset)));
+    }
+    Json::Obj(fields)
+}
@@ -0,0 +1,154 @@
+use std::{
+    io::{Write, stdout},
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+
+use filetime::FileTime;
+
+use crydec::{
+    archive::{Entry, EntryKind, Visitor, Packer, Unpacker, collect_sources, parse_rel_name},
+    error::Error,
+};
+
+use crate::{
+    cli::{Pack, Unpack, List},
+    command::{make_secrets, make_params, make_encryptor, decrypt_to},
+    control::{make_control, read_source, read_sources},
+    files::{Destination, make_input, make_output},
+};
+
+
+/// Encrypts archive of files and directories, content of files is streamed one file at a time
+pub fn pack(cfg: Pack) -> Result<(), Error> {
+    let passwords = read_sources(cfg.enc.pwd_sources(), false)?;
+    let (sources, skipped) = collect_sources(&cfg.paths)?;
+    for path in skipped {
+        eprintln!("WARNING: skipped {}: not a regular file or directory", path.display());
+    }
+    let mut output = make_output(cfg.fout, cfg.force)?;
+
+    let ctrl = make_control(false);
+    let secrets = make_secrets(&ctrl, &cfg.enc, passwords)?;
+    let params = make_params(&ctrl, &cfg.enc)?;
+    let encryptor = make_encryptor(params, secrets, cfg.enc.threads)?;
+
+    encryptor.encrypt(Packer::new(sources), &mut output)?;
+    output.commit()
+}
+
+
+/// Decrypts archive extracting its entries under output directory
+pub fn unpack(cfg: Unpack) -> Result<(), Error> {
+    let stdin_is_input = cfg.fin.is_none();
+    let pwd = read_source(cfg.secret.pwd_source(), stdin_is_input)?;
+    let input = make_input(cfg.fin)?;
+    std::fs::create_dir_all(&cfg.out_dir).map_err(Error::make_io)?;
+    let extractor = Extractor { out_dir: cfg.out_dir, force: cfg.force, output: None, dirs: Vec::new() };
+
+    let mut unpacker = Unpacker::new(extractor);
+    decrypt_to(input, stdin_is_input, None, cfg.threads, cfg.secret, pwd, &mut unpacker)?;
+    unpacker.finish()?.finish()
+}
+
+
+/// Decrypts archive printing its entries: mode, size, modification time and path
+pub fn list(cfg: List) -> Result<(), Error> {
+    let stdin_is_input = cfg.fin.is_none();
+    let pwd = read_source(cfg.secret.pwd_source(), stdin_is_input)?;
+    let input = make_input(cfg.fin)?;
+    let mut unpacker = Unpacker::new(Lister);
+    decrypt_to(input, stdin_is_input, None, cfg.threads, cfg.secret, pwd, &mut unpacker)?;
+    unpacker.finish()?;
+    Ok(())
+}
+
+
+/// Writes entries of archive to files, every file appears at its path only when its content is complete
+struct Extractor {
+    out_dir: PathBuf,
+    force: bool,
+    output: Option<Destination>,
+    /// Directories get their metadata at the end, as extracting their content changes it
+    dirs: Vec<(PathBuf, Entry)>,
+}
+
+impl Extractor {
+    fn finish(self) -> Result<(), Error> {
+        for (path, entry) in self.dirs.iter().rev() {
+            if let Some(permissions) = make_permissions(entry.mode) {
+                std::fs::set_permissions(path, permissions).map_err(Error::make_io)?;
+            }
+            filetime::set_file_mtime(path, FileTime::from_system_time(entry.mtime)).map_err(Error::make_io)?;
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for Extractor {
+    fn entry(&mut self, entry: &Entry) -> Result<(), Error> {
+        let path = self.out_dir.join(parse_rel_name(&entry.path)?);
+        match entry.kind {
+            EntryKind::File => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).map_err(Error::make_io)?;
+                }
+                let mut output = make_output(Some(path), self.force)?;
+                if let Some(permissions) = make_permissions(entry.mode) {
+                    output.set_permissions(permissions)?;
+                }
+                output.set_mtime(entry.mtime);
+                self.output = Some(output);
+            },
+            EntryKind::Dir => {
+                std::fs::create_dir_all(&path).map_err(Error::make_io)?;
+                self.dirs.push((path, entry.clone()));
+            },
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<(), Error> {
+        let output = self.output.as_mut().expect("content should follow file entry");
+        output.write_all(data).map_err(Error::make_io)
+    }
+
+    fn entry_end(&mut self) -> Result<(), Error> {
+        match self.output.take() {
+            Some(output) => output.commit(),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// Prints every entry of archive, content is discarded
+struct Lister;
+
+impl Visitor for Lister {
+    fn entry(&mut self, entry: &Entry) -> Result<(), Error> {
+        let mtime = entry.mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let slash = if entry.kind == EntryKind::Dir { "/" } else { "" };
+        writeln!(stdout(), "{:04o} {:>12} {} {}{}", entry.mode, entry.size, mtime, entry.path, slash).map_err(Error::make_io)
+    }
+
+    fn data(&mut self, _data: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn entry_end(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+
+/// Permissions of stored mode, other platforms keep default ones
+#[cfg(unix)]
+fn make_permissions(mode: u32) -> Option<std::fs::Permissions> {
+    Some(std::os::unix::fs::PermissionsExt::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn make_permissions(_mode: u32) -> Option<std::fs::Permissions> {
+    None
+}
@@ -0,0 +1,153 @@
+use std::{fmt, str::FromStr};
+
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::error::Error;
+
+
+/// Prefix of text encoded public key
+const RECIPIENT_PREFIX: &str = "crydec-pub-";
+/// Prefix of text encoded identity
+const IDENTITY_PREFIX: &str = "crydec-id-";
+/// HKDF info binding wrap key to its purpose
+const WRAP_INFO: &[u8] = b"crydec x25519 data key";
+
+
+/// Public key of X25519 key pair, data key is wrapped for it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    /// Makes key to wrap data key by ephemeral-static exchange, returns ephemeral public key and the key
+    pub fn make_wrap_key(&self) -> ([u8; 32], Zeroizing<[u8; 32]>) {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&self.0);
+        let wrap_key = make_wrap_key(shared.as_bytes(), ephemeral.as_bytes(), self.as_bytes());
+        (*ephemeral.as_bytes(), wrap_key)
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bytes = decode(text.trim(), RECIPIENT_PREFIX, "recipient")?;
+        Ok(Self(PublicKey::from(*bytes)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", RECIPIENT_PREFIX, hex::encode(self.as_bytes()))
+    }
+}
+
+
+/// Secret key of X25519 key pair, unwraps data key wrapped for its recipient
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn to_recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Makes the same key as recipient did using ephemeral public key of exchange
+    pub fn make_wrap_key(&self, ephemeral: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+        let shared = self.0.diffie_hellman(&PublicKey::from(*ephemeral));
+        make_wrap_key(shared.as_bytes(), ephemeral, self.to_recipient().as_bytes())
+    }
+
+    /// Parses identity file content, lines starting with `#` are comments
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let line = text.lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(|| Error::make_arg("identity file is empty"))?;
+        let bytes = decode(line, IDENTITY_PREFIX, "identity")?;
+        Ok(Self(StaticSecret::from(*bytes)))
+    }
+
+    /// Identity file content with public key as a comment
+    pub fn to_text(&self) -> Zeroizing<String> {
+        let secret = Zeroizing::new(hex::encode(self.0.as_bytes()));
+        Zeroizing::new(format!("# public key: {}\n{}{}\n", self.to_recipient(), IDENTITY_PREFIX, secret.as_str()))
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Identity").field(&self.to_recipient()).finish()
+    }
+}
+
+
+fn make_wrap_key(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(recipient);
+    let mut wrap_key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, wrap_key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    wrap_key
+}
+
+
+fn decode(text: &str, prefix: &str, name: &str) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let encoded = text.strip_prefix(prefix)
+        .ok_or_else(|| Error::make_arg(format!("{} should start with {}", name, prefix)))?;
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    hex::decode_to_slice(encoded, bytes.as_mut())
+        .map_err(|_| Error::make_arg(format!("{} should be {} followed by 64 hex digits", name, prefix)))?;
+    Ok(bytes)
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use super::{Identity, Recipient};
+
+
+    #[test]
+    fn make_wrap_key() {
+        let identity = Identity::generate();
+        let (ephemeral, wrap_key) = identity.to_recipient().make_wrap_key();
+        assert_eq!(wrap_key, identity.make_wrap_key(&ephemeral));
+        assert_ne!(wrap_key, Identity::generate().make_wrap_key(&ephemeral));
+
+        let (other_ephemeral, other_wrap_key) = identity.to_recipient().make_wrap_key();
+        assert_ne!(ephemeral, other_ephemeral);
+        assert_ne!(wrap_key, other_wrap_key);
+    }
+
+
+    #[test]
+    fn identity_recipient_text() {
+        let identity = Identity::generate();
+        let recipient = identity.to_recipient();
+        let parsed = Identity::parse(&identity.to_text()).unwrap();
+        assert_eq!(recipient, parsed.to_recipient());
+        assert_eq!(recipient, recipient.to_string().parse::<Recipient>().unwrap());
+
+        assert!(matches!("crydec-pub-00".parse::<Recipient>(), Err(Error::Arg(_))));
+        assert!(matches!(Identity::parse(&recipient.to_string()), Err(Error::Arg(_))));
+        assert!(matches!(Identity::parse("# comment only\n"), Err(Error::Arg(_))));
+    }
+}
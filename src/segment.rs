@@ -0,0 +1,297 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use chacha20poly1305::{XChaCha20Poly1305, KeyInit, aead::{Aead, Payload}};
+use hkdf::Hkdf;
+use rand::{RngCore, rngs::OsRng};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher::TAG_SIZE,
+    error::{Error, ChunkPos},
+};
+
+
+/// Size of frame in front of every segment: size of segment payload.
+/// Zero size marks segment being written, the real one is set only when segment is finished
+pub const FRAME_SIZE: usize = 8;
+
+/// Size of sealed count of segments: random nonce, count and tag
+const COUNT_LEN: usize = 24 + 8 + TAG_SIZE;
+
+/// Size of block of segment counts right after the spec. Count is kept twice and copies are
+/// overwritten in turn, so interrupted write damages only one of them
+pub const COUNTS_SIZE: usize = COUNT_LEN * 2;
+
+/// HKDF info binding key of segment count to its purpose
+const COUNT_INFO: &[u8] = b"crydec segment count";
+
+
+/// Nonce of STREAM of segment: nonce of spec with its last 4 bytes xored by segment index,
+/// so the first segment uses nonce of spec as is
+pub fn segment_nonce(nonce: &[u8], index: u32) -> Zeroizing<Vec<u8>> {
+    let mut res = Zeroizing::new(nonce.to_vec());
+    let tail = res.len() - 4;
+    for (byte, counter) in res[tail..].iter_mut().zip(index.to_be_bytes()) {
+        *byte ^= counter;
+    }
+    res
+}
+
+
+/// Seals and opens count of finished segments. Segments are counted once they are synced,
+/// so segments past the count are unfinished ones and missing counted segments are cut off
+pub struct Counter {
+    cipher: XChaCha20Poly1305,
+    aad: Vec<u8>,
+}
+
+impl Counter {
+    /// Count is sealed by key derived from cipher key, spec is its associated data
+    pub fn new(key: &[u8], aad: &[u8]) -> Self {
+        let mut count_key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, key)
+            .expand(COUNT_INFO, count_key.as_mut())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self { cipher: XChaCha20Poly1305::new(count_key.as_ref().into()), aad: aad.to_vec() }
+    }
+
+    /// Block of counts of payload without segments
+    pub fn make_block(&self) -> Result<Vec<u8>, Error> {
+        Ok([self.seal(0)?, self.seal(0)?].concat())
+    }
+
+    /// Seals count with random nonce, it is written over copy of block given by `open`
+    pub fn seal(&self, count: u64) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = self.cipher.encrypt(&nonce.into(), Payload { msg: &count.to_be_bytes(), aad: &self.aad })
+            .map_err(Error::make_aead)?;
+        Ok([&nonce[..], &sealed].concat())
+    }
+
+    /// Returns count of block for reading. Both copies have to authenticate: damaged newer copy
+    /// would hide segments cut off after the older count
+    pub fn open(&self, block: &[u8]) -> Result<u64, Error> {
+        match self.open_copies(block)? {
+            (Some(first), Some(second)) => Ok(first.max(second)),
+            _ => Err(Error::make_spec("count of segments failed authentication, it is damaged or its append was interrupted")),
+        }
+    }
+
+    /// Returns the highest count of block and offset of its copy to be overwritten by the next count.
+    /// Damaged copy left by interrupted write is tolerated, as it is the one overwritten
+    pub fn open_for_append(&self, block: &[u8]) -> Result<(u64, u64), Error> {
+        match self.open_copies(block)? {
+            (Some(first), Some(second)) if second > first => Ok((second, 0)),
+            (Some(first), _) => Ok((first, COUNT_LEN as u64)),
+            (None, Some(second)) => Ok((second, 0)),
+            (None, None) => Err(Error::make_spec("count of segments failed authentication, it is damaged")),
+        }
+    }
+
+    fn open_copies(&self, block: &[u8]) -> Result<(Option<u64>, Option<u64>), Error> {
+        if block.len() < COUNTS_SIZE {
+            return Err(Error::make_truncated(ChunkPos::new(0, 0, false)));
+        }
+        Ok((self.open_count(&block[..COUNT_LEN]), self.open_count(&block[COUNT_LEN..COUNTS_SIZE])))
+    }
+
+    fn open_count(&self, sealed: &[u8]) -> Option<u64> {
+        let (nonce, sealed) = sealed.split_at(24);
+        let count = self.cipher.decrypt(nonce.into(), Payload { msg: sealed, aad: &self.aad }).ok()?;
+        Some(u64::from_be_bytes(count.try_into().ok()?))
+    }
+}
+
+
+/// Finished segment of payload made of segments appended one by one
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Segment {
+    pub index: u32,
+    /// Offset of segment payload from the start of payload, its frame is right before it
+    pub offset: u64,
+    pub size: u64,
+    /// Number of chunks of previous segments
+    pub chunk_base: u64,
+}
+
+impl Segment {
+    /// Parses frame of segment following prev one, fails if segment is unfinished
+    pub fn parse(prev: Option<&Segment>, frame: &[u8], chunk_len: usize) -> Result<Self, Error> {
+        let (index, offset, chunk_base) = match prev {
+            Some(prev) => {
+                let index = prev.index.checked_add(1).ok_or_else(|| Error::make_spec("too many segments"))?;
+                (index, prev.end() + FRAME_SIZE as u64, prev.chunk_base + prev.chunk_count(chunk_len))
+            },
+            None => (0, FRAME_SIZE as u64, 0),
+        };
+        let size = match <[u8; FRAME_SIZE]>::try_from(frame) {
+            Ok(frame) => u64::from_be_bytes(frame),
+            Err(_) => 0,
+        };
+        let segment = Self { index, offset, size, chunk_base };
+        if size == 0 {
+            return Err(Error::make_truncated(segment.pos(0, chunk_len, false)));
+        }
+        Ok(segment)
+    }
+
+    /// Offset of frame of the next segment
+    pub fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+
+    pub fn chunk_count(&self, chunk_len: usize) -> u64 {
+        (self.size + chunk_len as u64 - 1) / chunk_len as u64
+    }
+
+    /// Position in payload of chunk with index inside segment
+    pub fn pos(&self, index: u64, chunk_len: usize, last: bool) -> ChunkPos {
+        let pos = ChunkPos::new(index, chunk_len, last);
+        ChunkPos { index: self.chunk_base + pos.index, offset: self.offset + pos.offset, last }
+    }
+
+    /// Moves chunk position of error made by STREAM of segment to position in payload
+    pub fn shift_err(&self, err: Error, chunk_len: usize) -> Error {
+        match err {
+            Error::Corrupted(pos) => Error::make_corrupted(self.pos(pos.index, chunk_len, pos.last)),
+            Error::Truncated(pos) => Error::make_truncated(self.pos(pos.index, chunk_len, pos.last)),
+            err => err,
+        }
+    }
+}
+
+
+/// End of segments found by `scan`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tail {
+    /// The last segment is finished
+    End,
+    /// Segment is being written, its writing was interrupted or it is not counted yet,
+    /// its frame is at the offset from the start of payload
+    Unfinished(u64),
+}
+
+
+/// Reads frames of count segments of payload starting at current position of reader, segment content is skipped.
+/// Fails if counted segment is unfinished or lasts past the end of reader
+pub fn scan<R: Read + Seek>(reader: &mut R, chunk_size: usize, count: u64) -> Result<(Vec<Segment>, Tail), Error> {
+    let chunk_len = chunk_size + TAG_SIZE;
+    let start = reader.stream_position().map_err(Error::make_io)?;
+    let end = reader.seek(SeekFrom::End(0)).map_err(Error::make_io)?;
+    let mut segments: Vec<Segment> = Vec::new();
+    loop {
+        let offset = segments.last().map_or(0, Segment::end);
+        if segments.len() as u64 == count {
+            let tail = if start + offset == end && count != 0 { Tail::End } else { Tail::Unfinished(offset) };
+            return Ok((segments, tail));
+        }
+        reader.seek(SeekFrom::Start(start + offset)).map_err(Error::make_io)?;
+        let mut frame = Vec::with_capacity(FRAME_SIZE);
+        reader.by_ref().take(FRAME_SIZE as u64).read_to_end(&mut frame).map_err(Error::make_io)?;
+        let segment = Segment::parse(segments.last(), &frame, chunk_len)?;
+        if start + segment.end() > end {
+            let index = (end - start - segment.offset) / chunk_len as u64;
+            return Err(Error::make_truncated(segment.pos(index, chunk_len, false)));
+        }
+        segments.push(segment);
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::error::{Error, ChunkPos};
+    use super::{Counter, Segment, Tail, FRAME_SIZE, COUNT_LEN, COUNTS_SIZE, scan, segment_nonce};
+
+
+    #[test]
+    fn nonce_per_segment() {
+        let nonce = [7u8; 19];
+        assert_eq!(&nonce[..], &segment_nonce(&nonce, 0)[..]);
+        assert_eq!(&[7, 7, 7, 6][..], &segment_nonce(&nonce, 1)[15..]);
+        assert_eq!(&[6, 7, 7, 7][..], &segment_nonce(&nonce, 1 << 24)[15..]);
+    }
+
+
+    #[test]
+    fn scan_frames() {
+        let chunk_size = 10;
+        let chunk_len = chunk_size + 16;
+        // Segments of 2 and 1 chunks after some prefix, then unfinished one
+        let mut data = b"prefix".to_vec();
+        for size in [chunk_len as u64 + 20, 20] {
+            data.extend_from_slice(&size.to_be_bytes());
+            data.resize(data.len() + size as usize, 0);
+        }
+        let mut reader = Cursor::new(&data);
+        reader.set_position(6);
+        let (segments, tail) = scan(&mut reader, chunk_size, 2).unwrap();
+        assert_eq!(Tail::End, tail);
+        assert_eq!(vec![
+            Segment { index: 0, offset: FRAME_SIZE as u64, size: chunk_len as u64 + 20, chunk_base: 0 },
+            Segment { index: 1, offset: FRAME_SIZE as u64 * 2 + chunk_len as u64 + 20, size: 20, chunk_base: 2 },
+        ], segments);
+
+        // Segment past the count is not finished yet
+        reader.set_position(6);
+        let (segments, tail) = scan(&mut reader, chunk_size, 1).unwrap();
+        assert_eq!(Tail::Unfinished(segments[0].end()), tail);
+
+        let end = data.len() as u64 - 6;
+        for unfinished in [&[0u8; FRAME_SIZE][..], &[0, 0, 1][..]] {
+            let data = [&data[..], unfinished].concat();
+            let mut reader = Cursor::new(&data);
+            reader.set_position(6);
+            assert_eq!(Tail::Unfinished(end), scan(&mut reader, chunk_size, 2).unwrap().1);
+            // Counted segment is unfinished
+            reader.set_position(6);
+            let res = scan(&mut reader, chunk_size, 3);
+            assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 3, .. }))));
+        }
+
+        // Segment lasts past the end, counted segment is missing
+        let mut reader = Cursor::new(&data[..data.len() - 1]);
+        reader.set_position(6);
+        let res = scan(&mut reader, chunk_size, 2);
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 2, .. }))));
+        let mut reader = Cursor::new(&data);
+        reader.set_position(6);
+        let res = scan(&mut reader, chunk_size, 3);
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 3, .. }))));
+    }
+
+
+    #[test]
+    fn counter_copies() {
+        let counter = Counter::new(&[5u8; 32], b"spec");
+        let mut block = counter.make_block().unwrap();
+        assert_eq!(COUNTS_SIZE, block.len());
+        assert_eq!((0, COUNT_LEN as u64), counter.open_for_append(&block).unwrap());
+
+        // Copies are overwritten in turn
+        for count in 1..4 {
+            let (_, copy) = counter.open_for_append(&block).unwrap();
+            block[copy as usize..copy as usize + COUNT_LEN].copy_from_slice(&counter.seal(count).unwrap());
+            assert_eq!(count, counter.open(&block).unwrap());
+            assert_eq!(count, counter.open_for_append(&block).unwrap().0);
+        }
+
+        // Append falls back to the older count of damaged copy and overwrites it, reading refuses it
+        let (_, copy) = counter.open_for_append(&block).unwrap();
+        let latest = COUNT_LEN - copy as usize;
+        block[latest] ^= 1;
+        assert_eq!((2, latest as u64), counter.open_for_append(&block).unwrap());
+        assert!(matches!(counter.open(&block), Err(Error::Spec(_))));
+        block[copy as usize] ^= 1;
+        assert!(matches!(counter.open_for_append(&block), Err(Error::Spec(_))));
+
+        let block = counter.make_block().unwrap();
+        assert!(matches!(Counter::new(&[5u8; 32], b"other").open(&block), Err(Error::Spec(_))));
+        assert!(matches!(Counter::new(&[6u8; 32], b"spec").open(&block), Err(Error::Spec(_))));
+        assert!(matches!(counter.open(&block[..COUNTS_SIZE - 1]), Err(Error::Truncated(_))));
+    }}
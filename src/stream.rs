@@ -0,0 +1,478 @@
+use std::io::{Read, Write, Seek, SeekFrom, Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+
+use crate::{
+    cipher::{Cipher, ChunkCipher, TAG_SIZE, make_stream},
+    error::{Error, ChunkPos},
+    segment::{self, Counter, Segment, Tail, COUNTS_SIZE, segment_nonce},
+};
+
+
+/// Encrypts plaintext written into it and writes ciphertext chunks to inner writer.
+/// `finish` has to be called after the last write, otherwise ciphertext is truncated.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    stream: Box<dyn ChunkCipher>,
+    position: u32,
+    aad: Vec<u8>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+}
+
+impl <W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8], chunk_size: usize) -> Self {
+        Self {
+            inner,
+            stream: make_stream(cipher, key, nonce),
+            position: 0,
+            aad: aad.to_vec(),
+            chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+        }
+    }
+
+    /// Encrypts pending plaintext as the last chunk, returns inner writer
+    pub fn finish(self) -> IoResult<W> {
+        let Self { mut inner, stream, position, aad, buf, .. } = self;
+        let ciphertext = stream.encrypt_chunk(position, true, &buf, &aad)?;
+        inner.write_all(&ciphertext)?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl <W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let n = buf.len().min(self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.chunk_size {
+            let ciphertext = self.stream.encrypt_chunk(self.position, false, &self.buf, &self.aad)?;
+            self.inner.write_all(&ciphertext)?;
+            self.buf.clear();
+            self.position = self.position.checked_add(1).ok_or_else(|| Error::make_aead(aead::Error))?;
+        }
+        Ok(n)
+    }
+
+    /// Flushes inner writer, pending plaintext stays buffered until its chunk is full
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// Reads ciphertext chunks from inner reader and returns decrypted plaintext
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    stream: Box<dyn ChunkCipher>,
+    position: u32,
+    is_done: bool,
+    aad: Vec<u8>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl <R: Read> DecryptReader<R> {
+    pub fn new(inner: R, cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8], chunk_size: usize) -> Self {
+        Self {
+            inner,
+            stream: make_stream(cipher, key, nonce),
+            position: 0,
+            is_done: false,
+            aad: aad.to_vec(),
+            chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Decrypts the next chunk into buffer, fails if stream ends before the last chunk
+    fn fill_buf(&mut self) -> IoResult<()> {
+        self.buf.clear();
+        self.pos = 0;
+        let chunk_len = self.chunk_size + TAG_SIZE;
+        let mut chunk = Vec::with_capacity(chunk_len);
+        self.inner.by_ref().take(chunk_len as u64).read_to_end(&mut chunk)?;
+        if chunk.len() == chunk_len {
+            self.buf = self.decrypt_chunk(false, &chunk)?;
+            self.position = self.position.checked_add(1).ok_or_else(|| Error::make_aead(aead::Error))?;
+        } else if chunk.is_empty() {
+            // Input ended before the last chunk, so the rest of stream is cut off
+            return Err(Error::make_truncated(ChunkPos::new(u64::from(self.position), chunk_len, false)).into());
+        } else {
+            self.buf = self.decrypt_chunk(true, &chunk)?;
+            self.is_done = true;
+        }
+        Ok(())
+    }
+
+    fn decrypt_chunk(&self, last: bool, chunk: &[u8]) -> Result<Vec<u8>, Error> {
+        self.stream.decrypt_chunk(self.position, last, chunk, &self.aad)
+            .map_err(|_| Error::make_chunk_failure(ChunkPos::new(u64::from(self.position), self.chunk_size + TAG_SIZE, last)))
+    }
+}
+
+impl <R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        while self.pos == self.buf.len() {
+            if self.is_done {
+                return Ok(0);
+            }
+            self.fill_buf()?;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+
+
+/// Decrypts ciphertext chunks of seekable inner reader on demand, so plaintext could be read
+/// from any offset. Only chunks covering read range are decrypted, the last chunk is
+/// authenticated on creation, so plaintext size is known and truncation is detected at once.
+pub struct SeekableDecryptor<R: Read + Seek> {
+    inner: R,
+    aad: Vec<u8>,
+    chunk_size: usize,
+    /// Offset of payload in inner reader
+    start: u64,
+    /// Payload of single stream is one segment
+    segments: Vec<SeekSegment>,
+    /// Index of segment and index of its chunk decrypted into buffer
+    loaded: Option<(usize, u64)>,
+    buf: Vec<u8>,
+    pos: u64,
+}
+
+/// Segment of payload with its STREAM and offset of its plaintext
+struct SeekSegment {
+    segment: Segment,
+    stream: Box<dyn ChunkCipher>,
+    base: u64,
+    size: u64,
+}
+
+impl <R: Read + Seek> SeekableDecryptor<R> {
+    /// Inner reader should be at the start of payload, payload is expected to last up to its end
+    pub fn new(mut inner: R, cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8], chunk_size: usize) -> Result<Self, Error> {
+        let start = inner.stream_position().map_err(Error::make_io)?;
+        let end = inner.seek(SeekFrom::End(0)).map_err(Error::make_io)?;
+        let segment = Segment { index: 0, offset: 0, size: end.saturating_sub(start), chunk_base: 0 };
+        let stream = make_stream(cipher, key, nonce);
+        Self::with_segments(inner, aad, chunk_size, start, vec![(segment, stream)])
+    }
+
+    /// The same as `new` for payload made of segments
+    pub fn new_segmented(mut inner: R, cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8], chunk_size: usize) -> Result<Self, Error> {
+        let mut block = Vec::with_capacity(COUNTS_SIZE);
+        inner.by_ref().take(COUNTS_SIZE as u64).read_to_end(&mut block).map_err(Error::make_io)?;
+        let count = Counter::new(key, aad).open(&block)?;
+        let start = inner.stream_position().map_err(Error::make_io)?;
+        let (segments, tail) = segment::scan(&mut inner, chunk_size, count)?;
+        if let Tail::Unfinished(_) = tail {
+            // Frame of unfinished segment is parsed again to get its position
+            Segment::parse(segments.last(), &[], chunk_size + TAG_SIZE)?;
+        }
+        let segments = segments.into_iter()
+            .map(|segment| (segment, make_stream(cipher, key, &segment_nonce(nonce, segment.index))))
+            .collect();
+        Self::with_segments(inner, aad, chunk_size, start, segments)
+    }
+
+    /// Authenticates the last chunk of every segment to get size of its plaintext
+    fn with_segments(inner: R, aad: &[u8], chunk_size: usize, start: u64, segments: Vec<(Segment, Box<dyn ChunkCipher>)>) -> Result<Self, Error> {
+        let mut decryptor = Self {
+            inner,
+            aad: aad.to_vec(),
+            chunk_size,
+            start,
+            segments: Vec::with_capacity(segments.len()),
+            loaded: None,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        let chunk_len = chunk_size + TAG_SIZE;
+        let mut base = 0;
+        for (segment, stream) in segments {
+            // The last chunk is always shorter than others, so payload of whole chunks is cut off
+            if segment.size % chunk_len as u64 == 0 {
+                return Err(Error::make_truncated(segment.pos(segment.size / chunk_len as u64, chunk_len, false)));
+            }
+            decryptor.segments.push(SeekSegment { segment, stream, base, size: 0 });
+            let idx = decryptor.segments.len() - 1;
+            let last = segment.chunk_count(chunk_len) - 1;
+            decryptor.load(idx, last)?;
+            let size = last * chunk_size as u64 + decryptor.buf.len() as u64;
+            decryptor.segments[idx].size = size;
+            base += size;
+        }
+        Ok(decryptor)
+    }
+
+    /// Size of plaintext
+    pub fn size(&self) -> u64 {
+        self.segments.last().map_or(0, |seg| seg.base + seg.size)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads and decrypts chunk of segment into buffer unless it is there already
+    fn load(&mut self, idx: usize, index: u64) -> Result<(), Error> {
+        if self.loaded == Some((idx, index)) {
+            return Ok(());
+        }
+        self.loaded = None;
+        let chunk_len = self.chunk_size + TAG_SIZE;
+        let seg = &self.segments[idx];
+        let is_last = index + 1 == seg.segment.chunk_count(chunk_len);
+        let pos = seg.segment.pos(index, chunk_len, is_last);
+        let position = u32::try_from(index).map_err(|_| Error::make_aead(aead::Error))?;
+
+        // The next segment follows the last chunk of segment
+        let limit = (chunk_len as u64).min(seg.segment.end() - pos.offset);
+        self.inner.seek(SeekFrom::Start(self.start + pos.offset)).map_err(Error::make_io)?;
+        let mut chunk = Vec::with_capacity(chunk_len);
+        self.inner.by_ref().take(limit).read_to_end(&mut chunk).map_err(Error::make_io)?;
+        // Inner reader could shrink since creation
+        if !is_last && chunk.len() < chunk_len {
+            return Err(Error::make_truncated(pos));
+        }
+        self.buf = seg.stream.decrypt_chunk(position, is_last, &chunk, &self.aad)
+            .map_err(|_| Error::make_chunk_failure(pos))?;
+        self.loaded = Some((idx, index));
+        Ok(())
+    }
+}
+
+impl <R: Read + Seek> Read for SeekableDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        // Segments with empty plaintext are skipped
+        let idx = self.segments.partition_point(|seg| seg.base + seg.size <= self.pos);
+        if idx == self.segments.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = self.pos - self.segments[idx].base;
+        let index = offset / self.chunk_size as u64;
+        self.load(idx, index)?;
+        let skip = (offset - index * self.chunk_size as u64) as usize;
+        let n = buf.len().min(self.buf.len() - skip);
+        buf[..n].copy_from_slice(&self.buf[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+
+impl <R: Read + Seek> Seek for SeekableDecryptor<R> {
+    /// Seeks over plaintext offsets, seeking beyond the end is allowed and reads nothing
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            },
+            SeekFrom::End(offset) => (self.size(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let pos = if offset < 0 {
+            base.checked_sub(offset.unsigned_abs())
+        } else {
+            base.checked_add(offset as u64)
+        };
+        self.pos = pos.ok_or_else(|| IoError::new(IoErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, Seek, SeekFrom, Cursor, copy};
+    use rand::{RngCore, rngs::OsRng};
+    use strum::IntoEnumIterator;
+    use crate::{
+        cipher::{Cipher, TAG_SIZE, make_stream, encrypt},
+        error::{Error, ChunkPos},
+        header::Header,
+        io::{Input, Output},
+    };
+    use super::{EncryptWriter, DecryptReader, SeekableDecryptor};
+
+
+    const AAD: &[u8] = b"serialized header";
+    const CHUNK_SIZE: usize = 500;
+
+
+    #[test]
+    fn writer_matches_transfer() {
+        for size in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 2 + 7] {
+            for cipher in Cipher::iter() {
+                let (key, nonce) = make_key_nonce(cipher);
+                let data = make_data(size);
+
+                let mut expected = Vec::new();
+                {
+                    let mut input = Input::new(Box::new( &data[..] ));
+                    let mut output = Output::new(Box::new( &mut expected ));
+                    encrypt(&*make_stream(cipher, &key, &nonce), AAD, CHUNK_SIZE, 1, &mut input, &mut output).unwrap();
+                }
+
+                // Odd sized writes to cross chunk borders
+                let mut writer = EncryptWriter::new(Vec::new(), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+                for part in data.chunks(77) {
+                    writer.write_all(part).unwrap();
+                }
+                let fact = writer.finish().unwrap();
+                assert_eq!(expected, fact, "size: {}, cipher: {:?}", size, cipher);
+            }
+        }
+    }
+
+
+    #[test]
+    fn writer_reader_roundtrip() {
+        for size in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE * 3 + 1] {
+            for cipher in Cipher::iter() {
+                let (key, nonce) = make_key_nonce(cipher);
+                let data = make_data(size);
+
+                let mut writer = EncryptWriter::new(Vec::new(), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+                copy(&mut &data[..], &mut writer).unwrap();
+                let enc = writer.finish().unwrap();
+
+                let mut reader = DecryptReader::new(&enc[..], cipher, &key, &nonce, AAD, CHUNK_SIZE);
+                let mut fact = Vec::new();
+                // Small reads to cross chunk borders
+                let mut buf = [0u8; 33];
+                loop {
+                    let n = reader.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    fact.extend_from_slice(&buf[..n]);
+                }
+                assert_eq!(data, fact, "size: {}, cipher: {:?}", size, cipher);
+            }
+        }
+    }
+
+
+    #[test]
+    fn reader_tampered() {
+        let cipher = Cipher::XChacha20Poly1305;
+        let (key, nonce) = make_key_nonce(cipher);
+        let mut writer = EncryptWriter::new(Vec::new(), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+        writer.write_all(&make_data(CHUNK_SIZE * 2)).unwrap();
+        let mut enc = writer.finish().unwrap();
+        enc[CHUNK_SIZE + 20] ^= 1;
+
+        let mut reader = DecryptReader::new(&enc[..], cipher, &key, &nonce, AAD, CHUNK_SIZE);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*err, Error::Corrupted(ChunkPos { index: 1, last: false, offset: o }) if o == (CHUNK_SIZE + TAG_SIZE) as u64));
+    }
+
+
+    #[test]
+    fn reader_truncated_boundaries() {
+        let cipher = Cipher::XChacha20Poly1305;
+        let (key, nonce) = make_key_nonce(cipher);
+        for size in [0, CHUNK_SIZE * 3, CHUNK_SIZE * 3 + 1] {
+            let mut writer = EncryptWriter::new(Vec::new(), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+            writer.write_all(&make_data(size)).unwrap();
+            let enc = writer.finish().unwrap();
+
+            // Every cut on chunk boundary before the end drops the last chunk
+            let chunk_len = CHUNK_SIZE + TAG_SIZE;
+            for idx in 0..=(enc.len() - 1) / chunk_len {
+                let mut reader = DecryptReader::new(&enc[..idx * chunk_len], cipher, &key, &nonce, AAD, CHUNK_SIZE);
+                let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+                let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+                assert!(matches!(*err, Error::Truncated(ChunkPos { index, .. }) if index == idx as u64), "size: {}, cut: {}", size, idx);
+            }
+        }
+    }
+
+
+    #[test]
+    fn seekable_random_access() {
+        let cipher = Cipher::Aes256Gcm;
+        let (key, nonce) = make_key_nonce(cipher);
+        for size in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 3 + 7] {
+            let data = make_data(size);
+            let mut writer = EncryptWriter::new(Vec::new(), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+            writer.write_all(&data).unwrap();
+            // Payload starts after some prefix, e.g. header
+            let enc = [&b"prefix"[..], &writer.finish().unwrap()].concat();
+            let mut inner = Cursor::new(&enc);
+            inner.seek(SeekFrom::Start(6)).unwrap();
+
+            let mut reader = SeekableDecryptor::new(inner, cipher, &key, &nonce, AAD, CHUNK_SIZE).unwrap();
+            assert_eq!(size as u64, reader.size());
+            // Ranges inside chunk, across chunk borders and beyond the end
+            for (start, len) in [(0, size), (size / 2, 3), (CHUNK_SIZE - 2, 4), (CHUNK_SIZE * 2, CHUNK_SIZE + 100), (size + 5, 1)] {
+                let expected = data.get(start..(start + len).min(size)).unwrap_or_default();
+                let mut fact = Vec::new();
+                assert_eq!(start as u64, reader.seek(SeekFrom::Start(start as u64)).unwrap());
+                reader.by_ref().take(len as u64).read_to_end(&mut fact).unwrap();
+                assert_eq!(expected, &fact[..], "size: {}, start: {}", size, start);
+            }
+            assert_eq!(size as u64, reader.seek(SeekFrom::End(0)).unwrap());
+            assert_eq!(0, reader.read(&mut [0u8; 8]).unwrap());
+            assert!(reader.seek(SeekFrom::Current(-(size as i64) - 1)).is_err());
+        }
+    }
+
+
+    #[test]
+    fn seekable_tampered_truncated() {
+        let cipher = Cipher::XChacha20Poly1305;
+        let (key, nonce) = make_key_nonce(cipher);
+        let mut writer = EncryptWriter::new(Vec::new(), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+        writer.write_all(&make_data(CHUNK_SIZE * 2 + 1)).unwrap();
+        let mut enc = writer.finish().unwrap();
+        let chunk_len = CHUNK_SIZE + TAG_SIZE;
+
+        // The last chunk is authenticated at once, cut on any chunk boundary is found
+        for idx in 0..3 {
+            let res = SeekableDecryptor::new(Cursor::new(&enc[..idx * chunk_len]), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+            assert!(matches!(res, Err(Error::Truncated(ChunkPos { index, .. })) if index == idx as u64), "cut: {}", idx);
+        }
+        let res = SeekableDecryptor::new(Cursor::new(&enc[..enc.len() - 1]), cipher, &key, &nonce, AAD, CHUNK_SIZE);
+        assert!(matches!(res, Err(Error::Truncated(ChunkPos { index: 2, last: true, .. }))));
+
+        // Damaged chunk fails only when it is read
+        enc[chunk_len + 20] ^= 1;
+        let mut reader = SeekableDecryptor::new(Cursor::new(&enc), cipher, &key, &nonce, AAD, CHUNK_SIZE).unwrap();
+        reader.read_exact(&mut [0u8; CHUNK_SIZE]).unwrap();
+        let err = reader.read(&mut [0u8; 1]).unwrap_err();
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*err, Error::Corrupted(ChunkPos { index: 1, last: false, .. })));
+    }
+
+
+    fn make_key_nonce(cipher: Cipher) -> (Vec<u8>, Vec<u8>) {
+        let (_, key_size, _) = cipher.get_spec();
+        let mut key = vec![0u8; key_size as usize];
+        OsRng.fill_bytes(&mut key);
+        let mut nonce = vec![0u8; Header::nonce_size(cipher)];
+        OsRng.fill_bytes(&mut nonce);
+        (key, nonce)
+    }
+
+
+    fn make_data(size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    fs::File,
+    io::{Read, Cursor, copy},
+    path::{Path, PathBuf},
+};
+
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+
+use crydec::{
+    error::Error,
+    archive::{make_rel_name, parse_rel_name},
+    header::Header,
+    io::Input,
+    Decryptor,
+};
+
+use crate::{
+    cli::{Encrypt, Decrypt, CfgTree},
+    command::{make_secrets, make_params, make_encryptor, choose_secret},
+    control::{make_control, read_source, read_sources},
+    files::{Destination, list_files, add_suffix, strip_suffix, make_output},
+};
+
+
+/// Size of random name of encrypted file with hidden name
+const HIDDEN_NAME_SIZE: usize = 16;
+
+
+/// Encrypts every file of directory tree to its own file under output directory
+pub fn encrypt(cfg: Encrypt) -> Result<(), Error> {
+    let passwords = read_sources(cfg.enc.pwd_sources(), false)?;
+    let (dir, out_dir) = make_dirs(&cfg.tree)?;
+    if cfg.enc.rand.nonce || cfg.enc.rand.nonce_cli.is_some() {
+        return Err(Error::make_arg("nonce can not be shared by files of recursive mode"));
+    }
+    let files = list_regular_files(&dir)?;
+
+    let ctrl = make_control(false);
+    let secrets = make_secrets(&ctrl, &cfg.enc, passwords)?;
+    let mut params = make_params(&ctrl, &cfg.enc)?;
+    // Every file gets its own data key and nonce, password slots share salt of the run,
+    // so password key is derived once
+    if params.salt.is_none() {
+        let mut salt = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(salt.as_mut());
+        params.salt = Some(salt);
+    }
+    let encryptor = make_encryptor(params, secrets, cfg.enc.threads)?;
+
+    for path in files {
+        let rel = path.strip_prefix(&dir).expect("listed file should be inside its directory");
+        let input = File::open(&path).map_err(Error::make_io)?;
+        if cfg.tree.hide_names {
+            let mut name = [0u8; HIDDEN_NAME_SIZE];
+            OsRng.fill_bytes(&mut name);
+            let target = out_dir.join(format!("{}{}", hex::encode(name), cfg.io.suffix));
+            let mut output = make_file_output(&target, &path, cfg.io.force)?;
+            encryptor.encrypt(Cursor::new(hidden_name(rel)?).chain(input), &mut output)?;
+            output.commit()?;
+        } else {
+            let target = add_suffix(&out_dir.join(rel), &cfg.io.suffix)?;
+            let mut output = make_file_output(&target, &path, cfg.io.force)?;
+            encryptor.encrypt(input, &mut output)?;
+            output.commit()?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Decrypts every encrypted file of directory tree restoring source tree under output directory
+pub fn decrypt(cfg: Decrypt) -> Result<(), Error> {
+    let pwd = read_source(cfg.secret.pwd_source(), false)?;
+    let (dir, out_dir) = make_dirs(&cfg.tree)?;
+    let files = list_regular_files(&dir)?.into_iter()
+        .filter(|path| {
+            let is_encrypted = path.file_name().map_or(false, |name| name.to_string_lossy().ends_with(&cfg.io.suffix));
+            if !is_encrypted {
+                eprintln!("WARNING: skipped {}: no {} suffix", path.display(), cfg.io.suffix);
+            }
+            is_encrypted
+        })
+        .collect::<Vec<_>>();
+
+    // Secret is chosen by key slots of the first file, files of one run share them
+    let header = match files.first() {
+        Some(path) => Header::read(&mut Input::new( Box::new( File::open(path).map_err(Error::make_io)? ) ))?,
+        None => return Ok(()),
+    };
+    let ctrl = make_control(false);
+    let secret = choose_secret(&ctrl, &header, cfg.secret, pwd)?;
+    let decryptor = Decryptor::new(secret).with_threads(cfg.threads.into());
+
+    for path in files {
+        let rel = path.strip_prefix(&dir).expect("listed file should be inside its directory");
+        let mut input = Input::new( Box::new( File::open(&path).map_err(Error::make_io)? ) );
+        let header = Header::read(&mut input)?;
+        if cfg.tree.hide_names {
+            let mut reader = decryptor.reader_with_header(&header, input.into_inner())?;
+            let target = out_dir.join(read_hidden_name(&mut reader)?);
+            let mut output = make_file_output(&target, &path, cfg.io.force)?;
+            copy(&mut reader, &mut output).map_err(Error::from)?;
+            output.commit()?;
+        } else {
+            let target = strip_suffix(&out_dir.join(rel), &cfg.io.suffix)?;
+            let mut output = make_file_output(&target, &path, cfg.io.force)?;
+            decryptor.decrypt_with_header(&header, input.into_inner(), &mut output)?;
+            output.commit()?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Returns input and output directories, output one is created if needed and can not be inside input one
+fn make_dirs(cfg: &CfgTree) -> Result<(PathBuf, PathBuf), Error> {
+    let dir = cfg.recursive.clone().expect("recursive mode should have input directory");
+    let out_dir = cfg.out_dir.clone().expect("recursive mode should have output directory");
+    let dir_abs = dir.canonicalize().map_err(Error::make_io)?;
+    // Missing part of output path is not a link, so the nearest existing ancestor tells where it is
+    let out_dir_abs = std::env::current_dir().map_err(Error::make_io)?.join(&out_dir);
+    let is_inside = out_dir_abs.ancestors()
+        .find_map(|path| path.canonicalize().ok())
+        .map_or(false, |path| path.starts_with(&dir_abs));
+    if is_inside {
+        return Err(Error::make_arg("output directory should be outside of input one"));
+    }
+    std::fs::create_dir_all(&out_dir).map_err(Error::make_io)?;
+    Ok((dir, out_dir))
+}
+
+
+/// Lists regular files of directory tree, anything else is skipped with a warning
+fn list_regular_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for path in list_files(dir)? {
+        if path.symlink_metadata().map_err(Error::make_io)?.is_file() {
+            files.push(path);
+        } else {
+            eprintln!("WARNING: skipped {}: not a regular file", path.display());
+        }
+    }
+    Ok(files)
+}
+
+
+/// Output file with permissions and modification time of source, its directory is created if needed
+fn make_file_output(path: &Path, source: &Path, force: bool) -> Result<Destination, Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(Error::make_io)?;
+    }
+    let mut output = make_output(Some(path.to_path_buf()), force)?;
+    output.copy_metadata(source)?;
+    Ok(output)
+}
+
+
+/// Relative path stored in front of plaintext of file with hidden name: u16 length and name made by `make_rel_name`
+fn hidden_name(rel: &Path) -> Result<Vec<u8>, Error> {
+    let name = make_rel_name(rel)?;
+    let size = u16::try_from(name.len())
+        .map_err(|_| Error::make_arg(format!("path is too long to hide it: {}", rel.display())))?;
+    Ok([&size.to_be_bytes()[..], name.as_bytes()].concat())
+}
+
+
+/// Reads relative path stored by `hidden_name`
+fn read_hidden_name(reader: &mut impl Read) -> Result<PathBuf, Error> {
+    let mut size = [0u8; 2];
+    reader.read_exact(&mut size).map_err(Error::from)?;
+    let mut name = vec![0u8; u16::from_be_bytes(size).into()];
+    reader.read_exact(&mut name).map_err(Error::from)?;
+    let name = String::from_utf8(name).map_err(|_| Error::make_spec("hidden file name should be utf-8"))?;
+    parse_rel_name(&name)
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crydec::error::Error;
+    use super::{hidden_name, read_hidden_name};
+
+
+    #[test]
+    fn hidden_name_roundtrip() {
+        let name = hidden_name(Path::new("dir/sub/file.txt")).unwrap();
+        assert_eq!(Path::new("dir/sub/file.txt"), read_hidden_name(&mut &name[..]).unwrap());
+
+        for name in ["../escape", "/root", "dir//file", "dir/./file", ""] {
+            let bytes = [&(name.len() as u16).to_be_bytes()[..], name.as_bytes()].concat();
+            assert!(matches!(read_hidden_name(&mut &bytes[..]), Err(Error::Spec(_))), "name: {}", name);
+        }
+        assert!(matches!(hidden_name(Path::new("../file")), Err(Error::Arg(_))));
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use crydec::{header::Header, io::Input};
+use rand::{RngCore, rngs::OsRng};
+
+
+#[test]
+fn verify_truncated_inside_chunk() {
+    let dir = make_dir();
+    let data = make_data(5000);
+    std::fs::write(dir.join("src"), &data).unwrap();
+    std::fs::write(dir.join("key"), make_data(32)).unwrap();
+    let out = run(&dir, &["encrypt", "--chunk-size", "1K", "--key-file", "key", "--fin", "src", "--fout", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = run(&dir, &["verify", "--key-file", "key", "--fin", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // File is cut in the middle of its fourth chunk
+    let enc = std::fs::read(dir.join("enc")).unwrap();
+    std::fs::write(dir.join("enc"), &enc[..enc.len() - 1500]).unwrap();
+    let out = run(&dir, &["--error-format", "json", "verify", "--key-file", "key", "--fin", "enc"]);
+    assert_eq!(Some(12), out.status.code());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(r#""error":"truncated""#) && stderr.contains(r#""chunk":3"#), "{}", stderr);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[test]
+fn verify_wrong_key() {
+    let dir = make_dir();
+    std::fs::write(dir.join("src"), make_data(100)).unwrap();
+    std::fs::write(dir.join("key"), make_data(32)).unwrap();
+    std::fs::write(dir.join("other"), make_data(32)).unwrap();
+    let out = run(&dir, &["encrypt", "--key-file", "key", "--fin", "src", "--fout", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = run(&dir, &["verify", "--key-file", "other", "--fin", "enc"]);
+    assert_eq!(Some(13), out.status.code());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("no key-file key slot could be opened"), "{}", stderr);
+
+    let out = run(&dir, &["verify", "--key-file", "other", "--fin", "enc", "--error-format", "json"]);
+    assert_eq!(Some(13), out.status.code());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(r#""error":"slot","code":13"#), "{}", stderr);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[test]
+fn encrypt_in_place() {
+    let dir = make_dir();
+    let data = make_data(5000);
+    std::fs::write(dir.join("src"), &data).unwrap();
+    std::fs::write(dir.join("key"), make_data(32)).unwrap();
+    let out = run(&dir, &["encrypt", "--in-place", "src", "--key-file", "key"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!dir.join("src").exists());
+
+    let out = run(&dir, &["decrypt", "--in-place", "src.crydec", "--key-file", "key"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(data, std::fs::read(dir.join("src")).unwrap());
+    assert!(!dir.join("src.crydec").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[test]
+fn verify_append_cut_at_segment() {
+    let dir = make_dir();
+    std::fs::write(dir.join("key"), make_data(32)).unwrap();
+    for (idx, size) in [1000, 2000].into_iter().enumerate() {
+        let src = format!("src{}", idx);
+        std::fs::write(dir.join(&src), make_data(size)).unwrap();
+        let out = run(&dir, &["encrypt", "--append", "--key-file", "key", "--fin", &src, "--fout", "enc"]);
+        assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+        if idx == 0 {
+            std::fs::copy(dir.join("enc"), dir.join("first")).unwrap();
+        }
+    }
+    let out = run(&dir, &["verify", "--key-file", "key", "--fin", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // File is cut right after its first segment
+    let first_len = std::fs::metadata(dir.join("first")).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(dir.join("enc")).unwrap().set_len(first_len).unwrap();
+    let out = run(&dir, &["verify", "--key-file", "key", "--fin", "enc"]);
+    assert_eq!(Some(12), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Newest copy of segment count is damaged too, so the older count does not hide the cut
+    let mut enc = std::fs::read(dir.join("enc")).unwrap();
+    let header_len = Header::read(&mut Input::new( Box::new(&enc[..]) )).unwrap().to_bytes().len();
+    enc[header_len] ^= 1;
+    std::fs::write(dir.join("enc"), &enc).unwrap();
+    for cmd in ["verify", "decrypt"] {
+        let out = run(&dir, &[cmd, "--key-file", "key", "--fin", "enc"]);
+        assert_eq!(Some(4), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(out.stdout.is_empty());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[test]
+fn password_sources_checked() {
+    let dir = make_dir();
+    let data = make_data(100);
+    std::fs::write(dir.join("src"), &data).unwrap();
+    let args = ["--lanes", "1", "--memory", "64", "--fin", "src", "--fout", "enc"];
+    let out = run_with_stdin(&dir, &[&["encrypt", "--pwd-fd", "0"], &args[..]].concat(), b"password\n");
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Stdin carrying data, descriptor not given to the process, empty variable are refused
+    let out = run_with_stdin(&dir, &["decrypt", "--pwd-fd", "0"], &std::fs::read(dir.join("enc")).unwrap());
+    assert_eq!(Some(2), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    let out = run(&dir, &["verify", "--pwd-fd", "3", "--fin", "enc"]);
+    assert_eq!(Some(2), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    let out = Command::new(env!("CARGO_BIN_EXE_crydec")).current_dir(&dir).env("CRYDEC_TEST_PWD", "")
+        .args(["verify", "--pwd-env", "CRYDEC_TEST_PWD", "--fin", "enc"]).output().unwrap();
+    assert_eq!(Some(2), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = run_with_stdin(&dir, &["decrypt", "--pwd-fd", "0", "--fin", "enc"], b"password");
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(data, out.stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[cfg(unix)]
+#[test]
+fn askpass_forced() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = make_dir();
+    let data = make_data(100);
+    std::fs::write(dir.join("src"), &data).unwrap();
+    std::fs::write(dir.join("pwd"), "password").unwrap();
+    let out = run(&dir, &["encrypt", "--lanes", "1", "--memory", "64", "--pwd-file", "pwd", "--fin", "src", "--fout", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Askpass is used even if TTY is attached
+    let script = dir.join("askpass.sh");
+    std::fs::write(&script, "#!/bin/sh\necho password\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_crydec")).current_dir(&dir)
+        .env("CRYDEC_ASKPASS", &script).env("CRYDEC_ASKPASS_REQUIRE", "force")
+        .args(["decrypt", "--fin", "enc"]).output().unwrap();
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(data, out.stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[cfg(unix)]
+#[test]
+fn password_command_stdin_closed() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = make_dir();
+    let data = make_data(100);
+    std::fs::write(dir.join("src"), &data).unwrap();
+    std::fs::write(dir.join("pwd"), "password").unwrap();
+    let out = run(&dir, &["encrypt", "--lanes", "1", "--memory", "64", "--pwd-file", "pwd", "--fin", "src", "--fout", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    let enc = std::fs::read(dir.join("enc")).unwrap();
+
+    // Command and askpass reading stdin get nothing when stdin is data input
+    let out = run_with_stdin(&dir, &["decrypt", "--pwd-cmd", "cat; echo password"], &enc);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(data, out.stdout);
+
+    let script = dir.join("askpass.sh");
+    std::fs::write(&script, "#!/bin/sh\ncat\necho password\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_crydec")).current_dir(&dir)
+        .env("CRYDEC_ASKPASS", &script).env("CRYDEC_ASKPASS_REQUIRE", "force").arg("decrypt")
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(&enc).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(data, out.stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[cfg(unix)]
+#[test]
+fn rekey_in_place() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = make_dir();
+    let data = make_data(5000);
+    std::fs::write(dir.join("src"), &data).unwrap();
+    std::fs::write(dir.join("key"), make_data(32)).unwrap();
+    std::fs::write(dir.join("new"), make_data(32)).unwrap();
+    let out = run(&dir, &["encrypt", "--key-file", "key", "--fin", "src", "--fout", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    let enc = std::fs::read(dir.join("enc")).unwrap();
+    let inode = std::fs::metadata(dir.join("enc")).unwrap().ino();
+
+    let out = run(&dir, &["rekey", "--fin", "enc", "--key-file", "key", "--new-key-file", "new"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Only spec is rewritten, the file is the same one
+    let rekeyed = std::fs::read(dir.join("enc")).unwrap();
+    let header_len = Header::read(&mut Input::new( Box::new(&enc[..]) )).unwrap().to_bytes().len();
+    assert_eq!(enc.len(), rekeyed.len());
+    assert_ne!(enc[..header_len], rekeyed[..header_len]);
+    assert_eq!(enc[header_len..], rekeyed[header_len..]);
+    assert_eq!(inode, std::fs::metadata(dir.join("enc")).unwrap().ino());
+
+    let out = run(&dir, &["decrypt", "--key-file", "new", "--fin", "enc"]);
+    assert_eq!(Some(0), out.status.code(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(data, out.stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+/// Runs the binary inside directory, so paths of arguments are relative to it
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_crydec")).current_dir(dir).args(args).output().unwrap()
+}
+
+
+fn run_with_stdin(dir: &Path, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_crydec")).current_dir(dir).args(args)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    // Refused run exits without reading its stdin
+    let _ = child.stdin.take().unwrap().write_all(stdin);
+    child.wait_with_output().unwrap()
+}
+
+
+fn make_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("crydec-test-{:016x}", OsRng.next_u64()));
+    std::fs::create_dir(&dir).unwrap();
+    dir
+}
+
+
+fn make_data(size: usize) -> Vec<u8> {
+    let mut data = vec![0u8; size];
+    OsRng.fill_bytes(&mut data);
+    data
+}